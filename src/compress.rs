@@ -0,0 +1,480 @@
+//! Compressing responses.
+//!
+//! This module is only available with the `compress` feature.
+#![cfg(feature = "compress")]
+
+use std::{io, mem};
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream;
+use hyper::{Body, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, HeaderMap, HeaderValue, VARY,
+};
+use crate::negotiate::select_encoding;
+use crate::request::Request;
+use crate::response::Response;
+
+
+//------------ Compression ---------------------------------------------------
+
+/// Compresses response bodies.
+///
+/// The encoding is negotiated via the Accept-Encoding header of the
+/// request. Brotli, gzip, and deflate are supported and preferred in
+/// that order if the client likes them equally.
+///
+/// Only bodies with a textual content type such as `text/*` or JSON are
+/// compressed. Content types that are compressed already, such as images,
+/// are left alone as are bodies smaller than a minimum size. Bodies of
+/// unknown size are always compressed as they are streamed. Large bodies
+/// of known size are compressed on Tokio’s blocking thread pool.
+///
+/// A compressed response gets a Content-Encoding header and a strong
+/// ETag is made weak. All responses that could have been compressed get
+/// a `Vary: Accept-Encoding` header.
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    /// The minimum size of a body of known size to be compressed.
+    min_size: u64,
+}
+
+impl Compression {
+    /// Creates a new value with default settings.
+    ///
+    /// The minimum size is 1024 bytes.
+    pub fn new() -> Self {
+        Compression { min_size: 1024 }
+    }
+
+    /// Sets the minimum size of a body of known size to be compressed.
+    pub fn min_size(self, min_size: u64) -> Self {
+        Compression { min_size }
+    }
+
+    /// Compresses a response to the given request if appropriate.
+    pub async fn compress(
+        &self, request: &Request, response: Response
+    ) -> Response {
+        self.compress_with(
+            Encoding::select(request.headers()), response
+        ).await
+    }
+
+    /// Wraps an operation so that all its responses are compressed.
+    ///
+    /// The returned value can be passed to [`serve`][crate::server::serve]
+    /// or [`ServerBuilder`][crate::server::ServerBuilder].
+    pub fn wrap<T, F, Fut>(
+        self, op: F
+    ) -> impl Fn(
+        Arc<T>, Request
+    ) -> BoxFuture<'static, Result<Response, Response>>
+        + Send + Sync + Clone + 'static
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        move |state, request| {
+            let encoding = Encoding::select(request.headers());
+            let fut = op(state, request);
+            async move {
+                match fut.await {
+                    Ok(res) => Ok(self.compress_with(encoding, res).await),
+                    Err(res) => Err(self.compress_with(encoding, res).await),
+                }
+            }.boxed()
+        }
+    }
+
+    async fn compress_with(
+        &self, encoding: Option<Encoding>, response: Response
+    ) -> Response {
+        let (mut parts, body) = response.into_hyper().into_parts();
+        if !self.is_compressible(parts.status, &parts.headers, &body) {
+            return Response::from_hyper(
+                hyper::Response::from_parts(parts, body)
+            )
+        }
+        parts.headers.append(
+            VARY, HeaderValue::from_static("Accept-Encoding")
+        );
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => {
+                return Response::from_hyper(
+                    hyper::Response::from_parts(parts, body)
+                )
+            }
+        };
+
+        parts.headers.insert(
+            CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str())
+        );
+        parts.headers.remove(CONTENT_LENGTH);
+        if let Some(etag) = parts.headers.get(ETAG) {
+            if etag.as_bytes().starts_with(b"\"") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                    parts.headers.insert(ETAG, weak);
+                }
+            }
+        }
+
+        let body = if body.size_hint().exact().is_some() {
+            // The body is in memory already, so we compress it in one go.
+            match compress_buffered(encoding, body).await {
+                Ok(body) => body,
+                Err(_) => return Response::internal_server_error()
+            }
+        }
+        else {
+            compress_stream(encoding, body)
+        };
+        Response::from_hyper(hyper::Response::from_parts(parts, body))
+    }
+
+    /// Returns whether the response should be compressed.
+    fn is_compressible(
+        &self, status: StatusCode, headers: &HeaderMap, body: &Body
+    ) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || headers.contains_key(CONTENT_ENCODING)
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false
+        }
+        if let Some(size) = body.size_hint().exact() {
+            if size < self.min_size {
+                return false
+            }
+        }
+        headers.get(CONTENT_TYPE).and_then(|value| {
+            value.to_str().ok()
+        }).is_some_and(is_compressible_type)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether a content type benefits from compression.
+fn is_compressible_type(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    let media_type = media_type.to_ascii_lowercase();
+    let (ty, subtype) = match media_type.split_once('/') {
+        Some(some) => some,
+        None => return false
+    };
+    ty == "text"
+        || subtype.ends_with("+json")
+        || subtype.ends_with("+xml")
+        || (ty == "application" && matches!(
+            subtype,
+            "json" | "javascript" | "ecmascript" | "xml" | "x-ndjson"
+            | "wasm"
+        ))
+}
+
+/// The size above which buffered bodies are compressed on a thread pool.
+///
+/// Compressing a large body takes long enough to hold up other tasks on
+/// the runtime’s worker, so it is moved to a blocking thread.
+const BLOCKING_SIZE: usize = 64 * 1024;
+
+async fn compress_buffered(
+    encoding: Encoding, body: Body
+) -> Result<Body, io::Error> {
+    let data = hyper::body::to_bytes(body).await.map_err(io::Error::other)?;
+    if data.len() <= BLOCKING_SIZE {
+        compress_data(encoding, &data)
+    }
+    else {
+        tokio::task::spawn_blocking(move || {
+            compress_data(encoding, &data)
+        }).await.map_err(io::Error::other)?
+    }
+}
+
+fn compress_data(encoding: Encoding, data: &[u8]) -> Result<Body, io::Error> {
+    let mut encoder = Encoder::new(encoding);
+    let mut res = encoder.write(data)?.to_vec();
+    res.extend_from_slice(&encoder.finish()?);
+    Ok(res.into())
+}
+
+fn compress_stream(encoding: Encoding, body: Body) -> Body {
+    Body::wrap_stream(stream::unfold(
+        Some((body, Encoder::new(encoding))),
+        |state| async move {
+            let (mut body, mut encoder) = state?;
+            loop {
+                match body.data().await {
+                    Some(Ok(chunk)) => match encoder.write(&chunk) {
+                        Ok(data) if data.is_empty() => continue,
+                        Ok(data) => {
+                            return Some((Ok(data), Some((body, encoder))))
+                        }
+                        Err(err) => return Some((Err(err), None))
+                    }
+                    Some(Err(err)) => {
+                        return Some((Err(io::Error::other(err)), None))
+                    }
+                    None => return Some((encoder.finish(), None))
+                }
+            }
+        }
+    ))
+}
+
+
+//------------ Encoding ------------------------------------------------------
+
+/// A content coding we support.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// All encodings in order of preference.
+    const ALL: [Encoding; 3] = [
+        Encoding::Brotli, Encoding::Gzip, Encoding::Deflate
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Selects the encoding to use based on the Accept-Encoding header.
+    ///
+    /// Returns `None` if the response should not be compressed.
+    fn select(headers: &HeaderMap) -> Option<Self> {
+        select_encoding(
+            headers, &Self::ALL.map(Self::as_str)
+        ).map(|idx| Self::ALL[idx])
+    }
+}
+
+
+//------------ Encoder -------------------------------------------------------
+
+/// A streaming compressor for one of our encodings.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => {
+                // Quality 5 is a reasonable trade-off for dynamic content.
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                    Vec::new(), 4096, 5, 22
+                )))
+            }
+            Encoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(
+                    Vec::new(), flate2::Compression::default()
+                ))
+            }
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(
+                    Vec::new(), flate2::Compression::default()
+                ))
+            }
+        }
+    }
+
+    /// Compresses data and returns whatever output is available.
+    fn write(&mut self, data: &[u8]) -> Result<Bytes, io::Error> {
+        let out = match self {
+            Encoder::Brotli(enc) => {
+                enc.write_all(data)?;
+                enc.get_mut()
+            }
+            Encoder::Gzip(enc) => {
+                enc.write_all(data)?;
+                enc.get_mut()
+            }
+            Encoder::Deflate(enc) => {
+                enc.write_all(data)?;
+                enc.get_mut()
+            }
+        };
+        Ok(mem::take(out).into())
+    }
+
+    /// Finishes compression and returns the remaining output.
+    fn finish(self) -> Result<Bytes, io::Error> {
+        match self {
+            Encoder::Brotli(enc) => Ok(enc.into_inner().into()),
+            Encoder::Gzip(enc) => enc.finish().map(Into::into),
+            Encoder::Deflate(enc) => enc.finish().map(Into::into),
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use super::*;
+    use hyper::header::ACCEPT_ENCODING;
+    use crate::etag::ETag;
+    use crate::response::{ContentType, ResponseBuilder};
+
+    fn select(accept: &'static str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept));
+        Encoding::select(&headers)
+    }
+
+    fn decode(encoding: &str, data: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        match encoding {
+            "br" => {
+                brotli::Decompressor::new(data, 4096)
+                    .read_to_end(&mut res).unwrap();
+            }
+            "gzip" => {
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut res).unwrap();
+            }
+            "deflate" => {
+                flate2::read::ZlibDecoder::new(data)
+                    .read_to_end(&mut res).unwrap();
+            }
+            _ => panic!("unexpected encoding {}", encoding)
+        };
+        res
+    }
+
+    #[test]
+    fn select_encoding() {
+        assert_eq!(select("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(select("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(
+            select("deflate;q=1, gzip;q=0.5"), Some(Encoding::Deflate)
+        );
+        assert_eq!(select("*"), Some(Encoding::Brotli));
+        assert_eq!(select("br;q=0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(select("identity"), None);
+        assert_eq!(select("gzip;q=0"), None);
+        assert_eq!(select("x-gzip"), Some(Encoding::Gzip));
+    }
+
+    #[tokio::test]
+    async fn compress() {
+        let data = "{\"roas\": []}".repeat(1000);
+        let response = |body: Body| {
+            ResponseBuilder::new().ok()
+                .content_type(ContentType::JSON)
+                .etag(&ETag::strong("foo").unwrap())
+                .body(body)
+        };
+
+        for encoding in ["br", "gzip", "deflate"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                ACCEPT_ENCODING, HeaderValue::from_static(encoding)
+            );
+            let encoding_value = Encoding::select(&headers);
+
+            // Buffered body.
+            let resp = Compression::new().compress_with(
+                encoding_value, response(data.clone().into())
+            ).await.into_hyper();
+            assert_eq!(resp.headers()[CONTENT_ENCODING], encoding);
+            assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+            assert_eq!(resp.headers()[ETAG], "W/\"foo\"");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert!(body.len() < data.len());
+            assert_eq!(decode(encoding, &body), data.as_bytes());
+
+            // Streamed body.
+            let (mut tx, body) = Body::channel();
+            let chunks = data.clone();
+            tokio::spawn(async move {
+                for chunk in chunks.as_bytes().chunks(100) {
+                    tx.send_data(
+                        Bytes::copy_from_slice(chunk)
+                    ).await.unwrap();
+                }
+            });
+            let resp = Compression::new().compress_with(
+                encoding_value, response(body)
+            ).await.into_hyper();
+            assert_eq!(resp.headers()[CONTENT_ENCODING], encoding);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(decode(encoding, &body), data.as_bytes());
+        }
+
+        // Large buffered body compressed on a blocking thread.
+        let large = data.repeat(10);
+        assert!(large.len() > BLOCKING_SIZE);
+        let resp = Compression::new().compress_with(
+            Some(Encoding::Gzip), response(large.clone().into())
+        ).await.into_hyper();
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(decode("gzip", &body), large.as_bytes());
+
+        // Small, uncompressible, and unaccepted responses.
+        let resp = Compression::new().compress_with(
+            Some(Encoding::Gzip), response("{}".into())
+        ).await.into_hyper();
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        assert!(!resp.headers().contains_key(VARY));
+
+        let resp = Compression::new().compress_with(
+            Some(Encoding::Gzip),
+            ResponseBuilder::new().ok()
+                .content_type(ContentType::external("image/png"))
+                .body(data.clone())
+        ).await.into_hyper();
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+
+        let resp = Compression::new().compress_with(
+            None, response(data.clone().into())
+        ).await.into_hyper();
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+    }
+
+    #[test]
+    fn wrap() {
+        // Only check that the wrapped op fits into the server.
+        async fn op(
+            _: Arc<()>, _: Request
+        ) -> Result<Response, Response> {
+            Err(Response::not_found())
+        }
+        drop(crate::server::ServerBuilder::new().serve(
+            Arc::new(()), Compression::new().wrap(op)
+        ));
+    }
+}
@@ -0,0 +1,440 @@
+//! Evaluating conditional requests.
+//!
+//! This module implements the preconditions of RFC 9110, section 13.
+//! Handlers describe the current state of a resource through
+//! [`Preconditions`] and evaluate the request against it.
+
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, Utc};
+use hyper::Method;
+use hyper::header::{HeaderMap, IF_MATCH, IF_NONE_MATCH, IF_RANGE, RANGE};
+#[cfg(feature = "chrono")]
+use hyper::header::{HeaderName, IF_MODIFIED_SINCE, IF_UNMODIFIED_SINCE};
+#[cfg(feature = "chrono")]
+use crate::date::parse_http_date;
+use crate::etag::{ETag, ETagList};
+use crate::request::Request;
+use crate::response::{Response, ResponseBuilder};
+
+
+//------------ Preconditions -------------------------------------------------
+
+/// The validators of the current representation of a resource.
+///
+/// Create a value with the validators you have, then call
+/// [`evaluate`][Self::evaluate] before processing the request:
+///
+/// ```
+/// # use httools::{Request, Response};
+/// # use httools::conditional::Preconditions;
+/// # use httools::etag::ETag;
+/// # use httools::response::ContentType;
+/// fn handle(request: &Request) -> Result<Response, Response> {
+///     Preconditions::new().etag(ETag::from_version(42)).evaluate(request)?;
+///     Ok(Response::ok(ContentType::TEXT, "version 42"))
+/// }
+/// ```
+///
+/// The modification date is only available with the `chrono` feature.
+/// Without it, the date-based conditions are ignored as they would be
+/// for a resource without a modification date.
+#[derive(Clone, Debug)]
+pub struct Preconditions {
+    /// Whether the resource currently has a representation.
+    exists: bool,
+
+    /// The entity tag of the representation.
+    etag: Option<ETag>,
+
+    /// The modification date of the representation.
+    #[cfg(feature = "chrono")]
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Preconditions {
+    /// Creates the preconditions for an existing resource.
+    pub fn new() -> Self {
+        Preconditions {
+            exists: true,
+            etag: None,
+            #[cfg(feature = "chrono")]
+            last_modified: None,
+        }
+    }
+
+    /// Creates the preconditions for a resource that doesn’t exist.
+    ///
+    /// This is useful for a PUT request that creates a new resource.
+    pub fn absent() -> Self {
+        Preconditions { exists: false, ..Self::new() }
+    }
+
+    /// Sets the entity tag of the current representation.
+    pub fn etag(self, etag: ETag) -> Self {
+        Preconditions { etag: Some(etag), ..self }
+    }
+
+    /// Sets the modification date of the current representation.
+    #[cfg(feature = "chrono")]
+    pub fn last_modified(self, last_modified: DateTime<Utc>) -> Self {
+        Preconditions { last_modified: Some(last_modified), ..self }
+    }
+
+    /// Evaluates the preconditions of a request.
+    ///
+    /// If the request should be processed, returns `Ok(())`. Otherwise
+    /// returns either a Not Modified response for GET and HEAD requests
+    /// or a Precondition Failed response for all other methods.
+    ///
+    /// The conditions are evaluated in the order given in RFC 9110,
+    /// section 13.2.2. If-Range is not evaluated here but via
+    /// [`if_range`][Self::if_range].
+    pub fn evaluate(&self, request: &Request) -> Result<(), Response> {
+        self.evaluate_headers(request.method(), request.headers())
+    }
+
+    /// Evaluates the If-Range condition of a request.
+    ///
+    /// Returns whether the Range header of the request should be honoured.
+    /// This is the case if there is no If-Range header or the condition
+    /// is true. Entity tags are compared using the strong comparison and
+    /// dates must match exactly.
+    pub fn if_range(&self, request: &Request) -> bool {
+        self.if_range_headers(request.headers())
+    }
+
+    /// Returns the response if the request would be answered with a 304.
+    ///
+    /// This only considers If-None-Match and If-Modified-Since.
+    #[cfg(feature = "chrono")]
+    pub(crate) fn not_modified(
+        &self, request: &Request
+    ) -> Option<Response> {
+        if !is_safe(request.method()) {
+            return None
+        }
+        match self.none_match(request.headers()) {
+            Some(true) => return None,
+            Some(false) => return Some(self.not_modified_response()),
+            None => { }
+        }
+        if self.modified_since(request.headers()) == Some(false) {
+            return Some(self.not_modified_response())
+        }
+        None
+    }
+
+    fn evaluate_headers(
+        &self, method: &Method, headers: &HeaderMap
+    ) -> Result<(), Response> {
+        // Step 1 and 2: If-Match or else If-Unmodified-Since.
+        let matched = match self.if_match(headers) {
+            Some(matched) => Some(matched),
+            None => self.unmodified_since(headers),
+        };
+        if matched == Some(false) {
+            return Err(Response::precondition_failed())
+        }
+
+        // Step 3 and 4: If-None-Match or else If-Modified-Since.
+        let modified = match self.none_match(headers) {
+            Some(modified) => Some(modified),
+            None if is_safe(method) => self.modified_since(headers),
+            None => None,
+        };
+        if modified == Some(false) {
+            if is_safe(method) {
+                return Err(self.not_modified_response())
+            }
+            else {
+                return Err(Response::precondition_failed())
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates If-Match if present.
+    ///
+    /// A malformed header is treated as a condition that is false.
+    fn if_match(&self, headers: &HeaderMap) -> Option<bool> {
+        Some(match ETagList::from_headers(headers, &IF_MATCH) {
+            Ok(Some(ETagList::Any)) => self.exists,
+            Ok(Some(tags)) => {
+                self.etag.as_ref().is_some_and(|etag| {
+                    tags.matches_strong(etag)
+                })
+            }
+            Ok(None) => return None,
+            Err(_) => false,
+        })
+    }
+
+    /// Evaluates If-Unmodified-Since if present and applicable.
+    #[cfg(feature = "chrono")]
+    fn unmodified_since(&self, headers: &HeaderMap) -> Option<bool> {
+        let date = header_date(headers, &IF_UNMODIFIED_SINCE)?;
+        Some(self.last_modified?.timestamp() <= date.timestamp())
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn unmodified_since(&self, _headers: &HeaderMap) -> Option<bool> {
+        None
+    }
+
+    /// Evaluates If-None-Match if present.
+    ///
+    /// Returns whether the representation has been modified, i.e.,
+    /// whether none of the tags matched. A malformed header is ignored.
+    fn none_match(&self, headers: &HeaderMap) -> Option<bool> {
+        match ETagList::from_headers(headers, &IF_NONE_MATCH) {
+            Ok(Some(ETagList::Any)) => Some(!self.exists),
+            Ok(Some(tags)) => {
+                Some(!self.etag.as_ref().is_some_and(|etag| {
+                    tags.matches_weak(etag)
+                }))
+            }
+            Ok(None) | Err(_) => None,
+        }
+    }
+
+    /// Evaluates If-Modified-Since if present and applicable.
+    #[cfg(feature = "chrono")]
+    fn modified_since(&self, headers: &HeaderMap) -> Option<bool> {
+        let date = header_date(headers, &IF_MODIFIED_SINCE)?;
+        Some(self.last_modified?.timestamp() > date.timestamp())
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn modified_since(&self, _headers: &HeaderMap) -> Option<bool> {
+        None
+    }
+
+    fn if_range_headers(&self, headers: &HeaderMap) -> bool {
+        if !headers.contains_key(RANGE) {
+            return true
+        }
+        let value = match headers.get(IF_RANGE) {
+            Some(value) => value,
+            None => return true
+        };
+        let value = match value.to_str() {
+            Ok(value) => value.trim(),
+            Err(_) => return false
+        };
+        if value.starts_with('"') || value.starts_with("W/") {
+            return match (ETag::parse(value), self.etag.as_ref()) {
+                (Ok(tag), Some(etag)) => tag.strong_eq(etag),
+                _ => false
+            }
+        }
+        self.if_range_date(value)
+    }
+
+    #[cfg(feature = "chrono")]
+    fn if_range_date(&self, value: &str) -> bool {
+        match (parse_http_date(value), self.last_modified) {
+            (Some(date), Some(last_modified)) => {
+                date.timestamp() == last_modified.timestamp()
+            }
+            _ => false
+        }
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn if_range_date(&self, _value: &str) -> bool {
+        false
+    }
+
+    fn not_modified_response(&self) -> Response {
+        let mut res = ResponseBuilder::new().not_modified();
+        if let Some(etag) = self.etag.as_ref() {
+            res = res.etag(etag);
+        }
+        #[cfg(feature = "chrono")]
+        if let Some(last_modified) = self.last_modified {
+            res = res.last_modified(last_modified);
+        }
+        res.empty()
+    }
+}
+
+impl Default for Preconditions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns whether the method is one answered with 304 Not Modified.
+fn is_safe(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// Parses the date of the first header with the given name.
+///
+/// Returns `None` if there is no such header or it isn’t a valid date.
+#[cfg(feature = "chrono")]
+fn header_date(
+    headers: &HeaderMap, name: &HeaderName
+) -> Option<DateTime<Utc>> {
+    parse_http_date(headers.get(name)?.to_str().ok()?)
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::StatusCode;
+    use hyper::header::{HeaderName, HeaderValue};
+
+    fn eval(
+        cond: &Preconditions, method: Method,
+        headers: &[(HeaderName, &'static str)],
+    ) -> StatusCode {
+        let mut map = HeaderMap::new();
+        for (key, value) in headers {
+            map.append(key, HeaderValue::from_static(value));
+        }
+        match cond.evaluate_headers(&method, &map) {
+            Ok(()) => StatusCode::OK,
+            Err(resp) => resp.into_hyper().status(),
+        }
+    }
+
+    #[test]
+    fn evaluate() {
+        use Method as M;
+        use StatusCode as S;
+
+        let strong = Preconditions::new().etag(ETag::strong("v1").unwrap());
+        let weak = Preconditions::new().etag(ETag::weak("v1").unwrap());
+        let absent = Preconditions::absent();
+
+        // If-Match uses strong comparison.
+        assert_eq!(eval(&strong, M::PUT, &[(IF_MATCH, "\"v1\"")]), S::OK);
+        assert_eq!(
+            eval(&strong, M::PUT, &[(IF_MATCH, "\"v0\", \"v1\"")]), S::OK
+        );
+        assert_eq!(
+            eval(&weak, M::PUT, &[(IF_MATCH, "W/\"v1\"")]),
+            S::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            eval(&strong, M::PUT, &[(IF_MATCH, "\"v0\"")]),
+            S::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            eval(&strong, M::PUT, &[(IF_MATCH, "\"v1\" x")]),
+            S::PRECONDITION_FAILED
+        );
+        assert_eq!(eval(&strong, M::PUT, &[(IF_MATCH, "*")]), S::OK);
+        assert_eq!(
+            eval(&absent, M::PUT, &[(IF_MATCH, "*")]),
+            S::PRECONDITION_FAILED
+        );
+
+        // If-None-Match uses weak comparison.
+        assert_eq!(
+            eval(&weak, M::GET, &[(IF_NONE_MATCH, "\"v1\"")]),
+            S::NOT_MODIFIED
+        );
+        assert_eq!(
+            eval(&strong, M::HEAD, &[(IF_NONE_MATCH, "W/\"v1\"")]),
+            S::NOT_MODIFIED
+        );
+        assert_eq!(
+            eval(&strong, M::GET, &[(IF_NONE_MATCH, "\"v0\"")]), S::OK
+        );
+        assert_eq!(
+            eval(&strong, M::DELETE, &[(IF_NONE_MATCH, "\"v1\"")]),
+            S::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            eval(&strong, M::PUT, &[(IF_NONE_MATCH, "*")]),
+            S::PRECONDITION_FAILED
+        );
+        assert_eq!(eval(&absent, M::PUT, &[(IF_NONE_MATCH, "*")]), S::OK);
+        assert_eq!(
+            eval(&strong, M::GET, &[(IF_NONE_MATCH, "\"v1\" x")]), S::OK
+        );
+
+        // If-Match takes precedence over If-None-Match.
+        assert_eq!(
+            eval(&strong, M::GET, &[
+                (IF_MATCH, "\"v0\""), (IF_NONE_MATCH, "\"v0\"")
+            ]),
+            S::PRECONDITION_FAILED
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn evaluate_dates() {
+        use chrono::TimeZone;
+        use Method as M;
+        use StatusCode as S;
+
+        const BEFORE: &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+        const AT: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+        const AFTER: &str = "Sun, 06 Nov 1994 08:49:38 GMT";
+
+        let cond = Preconditions::new().etag(
+            ETag::strong("v1").unwrap()
+        ).last_modified(
+            Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap()
+        );
+
+        assert_eq!(
+            eval(&cond, M::GET, &[(IF_MODIFIED_SINCE, AT)]), S::NOT_MODIFIED
+        );
+        assert_eq!(
+            eval(&cond, M::GET, &[(IF_MODIFIED_SINCE, BEFORE)]), S::OK
+        );
+        assert_eq!(eval(&cond, M::PUT, &[(IF_MODIFIED_SINCE, AT)]), S::OK);
+        assert_eq!(
+            eval(&cond, M::GET, &[(IF_MODIFIED_SINCE, "garbage")]), S::OK
+        );
+
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert_eq!(
+            eval(&cond, M::GET, &[
+                (IF_NONE_MATCH, "\"v0\""), (IF_MODIFIED_SINCE, AFTER)
+            ]),
+            S::OK
+        );
+
+        assert_eq!(
+            eval(&cond, M::PUT, &[(IF_UNMODIFIED_SINCE, AT)]), S::OK
+        );
+        assert_eq!(
+            eval(&cond, M::PUT, &[(IF_UNMODIFIED_SINCE, BEFORE)]),
+            S::PRECONDITION_FAILED
+        );
+
+        // If-Match takes precedence over If-Unmodified-Since.
+        assert_eq!(
+            eval(&cond, M::PUT, &[
+                (IF_MATCH, "\"v1\""), (IF_UNMODIFIED_SINCE, BEFORE)
+            ]),
+            S::OK
+        );
+
+        // If-Range.
+        let if_range = |value: &'static str| {
+            let mut map = HeaderMap::new();
+            map.insert(RANGE, HeaderValue::from_static("bytes=0-10"));
+            map.insert(IF_RANGE, HeaderValue::from_static(value));
+            cond.if_range_headers(&map)
+        };
+        assert!(if_range("\"v1\""));
+        assert!(!if_range("W/\"v1\""));
+        assert!(!if_range("\"v0\""));
+        assert!(if_range(AT));
+        assert!(!if_range(BEFORE));
+        assert!(cond.if_range_headers(&HeaderMap::new()));
+    }
+}
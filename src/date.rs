@@ -110,12 +110,9 @@ mod test {
 
     #[test]
     fn test_parse_http_date() {
-        let date = DateTime::<Utc>::from_utc(
-            chrono::naive::NaiveDate::from_ymd(
-                1994, 11, 6
-            ).and_hms(8, 49, 37),
-            Utc
-        );
+        use chrono::TimeZone;
+
+        let date = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
 
         assert_eq!(
             parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
@@ -0,0 +1,290 @@
+//! Embedding assets into the binary.
+//!
+//! A build script can use [`AssetTableBuilder`] to generate a table of
+//! assets for [`StaticFiles::assets`] from all files of a directory. The
+//! entity tags of the assets are calculated at build time and, with the
+//! `compress` feature, pre-compressed variants can be added:
+//!
+//! ```no_run
+//! // build.rs
+//! use std::env;
+//! use std::path::Path;
+//! use httools::embed::AssetTableBuilder;
+//!
+//! fn main() {
+//!     let out_dir = env::var("OUT_DIR").unwrap();
+//!     AssetTableBuilder::new("assets").write(
+//!         Path::new(&out_dir).join("assets.rs")
+//!     ).unwrap();
+//! }
+//! ```
+//!
+//! The generated file contains an array expression that is included
+//! into the crate:
+//!
+//! ```ignore
+//! use httools::files::StaticFiles;
+//!
+//! let files = StaticFiles::assets(
+//!     include!(concat!(env!("OUT_DIR"), "/assets.rs"))
+//! );
+//! ```
+//!
+//! This requires `httools` to be both a regular and a build dependency.
+//!
+//! There is no separate lookup for embedded assets. They are looked up
+//! and served by [`StaticFiles::serve`] using the request’s
+//! [`RequestPath`] or by [`StaticFiles::serve_path`] using a path taken
+//! from a router parameter. Both answer conditional requests with the
+//! precomputed entity tags and pick a pre-compressed variant if the
+//! client accepts it.
+//!
+//! [`RequestPath`]: crate::request::RequestPath
+//! [`StaticFiles::assets`]: crate::files::StaticFiles::assets
+//! [`StaticFiles::serve`]: crate::files::StaticFiles::serve
+//! [`StaticFiles::serve_path`]: crate::files::StaticFiles::serve_path
+
+use std::{fmt, io};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::etag::ETag;
+
+
+//------------ AssetTableBuilder ---------------------------------------------
+
+/// Generates the code for a table of assets from a directory.
+///
+/// This is meant to be used in a build script. All files in the
+/// directory and its subdirectories are included except for those whose
+/// name starts with a dot. Symbolic links are followed. Anything that
+/// isn’t a regular file or directory is skipped.
+#[derive(Clone, Debug)]
+pub struct AssetTableBuilder {
+    /// The directory with the assets.
+    dir: PathBuf,
+
+    /// Whether to add compressed variants.
+    #[cfg(feature = "compress")]
+    precompress: bool,
+}
+
+impl AssetTableBuilder {
+    /// Creates a builder for the assets in the given directory.
+    ///
+    /// A relative path is relative to the current directory which for
+    /// a build script is the directory of the package’s manifest.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        AssetTableBuilder {
+            dir: dir.into(),
+            #[cfg(feature = "compress")]
+            precompress: false,
+        }
+    }
+
+    /// Sets whether to add compressed variants of the assets.
+    ///
+    /// If enabled, Brotli and gzip variants are created with the highest
+    /// compression level. A variant is only kept if it saves at least a
+    /// tenth of the size.
+    #[cfg(feature = "compress")]
+    pub fn precompress(self, precompress: bool) -> Self {
+        AssetTableBuilder { precompress, ..self }
+    }
+
+    /// Writes the code for the asset table to the given file.
+    ///
+    /// Compressed variants are written next to the file. The method
+    /// also tells Cargo to re-run the build script if the directory
+    /// changes.
+    pub fn write(&self, target: impl AsRef<Path>) -> Result<(), io::Error> {
+        let target = target.as_ref();
+        let root = fs::canonicalize(&self.dir)?;
+        let mut files = Vec::new();
+        collect_files(&root, "", &mut files)?;
+        files.sort();
+        println!("cargo:rerun-if-changed={}", root.display());
+
+        let mut code = String::from("[\n");
+        for (idx, (path, file)) in files.iter().enumerate() {
+            let content = fs::read(file)?;
+            write!(
+                code,
+                "    ::httools::files::Asset::new(\n        \
+                 {:?},\n        include_bytes!({:?}),\n    )\n    \
+                 .etag({:?})",
+                path,
+                path_str(file)?,
+                ETag::from_bytes(&content).as_str(),
+            ).expect("writing to string failed");
+            #[cfg(feature = "compress")]
+            if self.precompress {
+                self.write_variants(target, idx, &content, &mut code)?;
+            }
+            #[cfg(not(feature = "compress"))]
+            let _ = idx;
+            code.push_str(",\n");
+        }
+        code.push_str("]\n");
+        fs::write(target, code)
+    }
+
+    #[cfg(feature = "compress")]
+    fn write_variants(
+        &self, target: &Path, idx: usize, content: &[u8], code: &mut String
+    ) -> Result<(), io::Error> {
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+        for (method, ext, data) in [
+            ("brotli", "br", compress_brotli(content)?),
+            ("gzip", "gz", compress_gzip(content)?),
+        ] {
+            if data.len() > content.len() - content.len() / 10 {
+                continue
+            }
+            let file = target.with_file_name(
+                format!("{}.{}.{}", stem, idx, ext)
+            );
+            fs::write(&file, data)?;
+            write!(
+                code, "\n    .{}(include_bytes!({:?}))",
+                method, path_str(&file)?
+            ).expect("writing to string failed");
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Collects all non-hidden files below `dir`.
+///
+/// Adds pairs of the path relative to the directory and the file system
+/// path to `res`. Symbolic links are followed.
+fn collect_files(
+    dir: &Path, prefix: &str, res: &mut Vec<(String, PathBuf)>
+) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_str().ok_or_else(|| {
+            non_utf8_path(&entry.path())
+        })?;
+        if name.starts_with('.') {
+            continue
+        }
+        let path = format!("{}{}", prefix, name);
+        let meta = fs::metadata(entry.path())?;
+        if meta.is_dir() {
+            collect_files(&entry.path(), &format!("{}/", path), res)?;
+        }
+        else if meta.is_file() {
+            res.push((path, entry.path()))
+        }
+    }
+    Ok(())
+}
+
+fn path_str(path: &Path) -> Result<&str, io::Error> {
+    path.to_str().ok_or_else(|| non_utf8_path(path))
+}
+
+fn non_utf8_path(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        NonUtf8Path(path.to_string_lossy().into())
+    )
+}
+
+#[cfg(feature = "compress")]
+fn compress_brotli(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    use std::io::Write;
+
+    let mut enc = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+    enc.write_all(data)?;
+    Ok(enc.into_inner())
+}
+
+#[cfg(feature = "compress")]
+fn compress_gzip(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    use std::io::Write;
+
+    let mut enc = flate2::write::GzEncoder::new(
+        Vec::new(), flate2::Compression::best()
+    );
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+
+//------------ NonUtf8Path ---------------------------------------------------
+
+/// A path in the asset directory isn’t valid UTF-8.
+#[derive(Debug)]
+struct NonUtf8Path(String);
+
+impl fmt::Display for NonUtf8Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "path is not valid UTF-8: {}", self.0)
+    }
+}
+
+impl std::error::Error for NonUtf8Path { }
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let root = std::env::temp_dir().join(format!(
+            "httools-embed-test-{}", std::process::id()
+        ));
+        let dir = root.join("assets");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        fs::write(dir.join("a.css"), "a".repeat(1000)).unwrap();
+        fs::write(dir.join(".git/config"), "x").unwrap();
+        #[cfg(unix)]
+        {
+            let linked = root.join("linked");
+            fs::create_dir_all(&linked).unwrap();
+            fs::write(linked.join("c.js"), "c").unwrap();
+            std::os::unix::fs::symlink(&linked, dir.join("link")).unwrap();
+        }
+
+        let target = root.join("assets.rs");
+        let builder = AssetTableBuilder::new(&dir);
+        #[cfg(feature = "compress")]
+        let builder = builder.precompress(true);
+        builder.write(&target).unwrap();
+        let code = fs::read_to_string(&target).unwrap();
+
+        assert!(code.starts_with("[\n"));
+        assert!(code.ends_with("]\n"));
+        let a = code.find(
+            "::httools::files::Asset::new(\n        \"a.css\","
+        ).unwrap();
+        let b = code.find("\"sub/b.txt\"").unwrap();
+        assert!(a < b);
+        assert!(code.contains(&format!(
+            ".etag({:?})", ETag::from_bytes(b"b").as_str()
+        )));
+        assert!(!code.contains(".git"));
+        #[cfg(unix)]
+        assert!(code.contains("\"link/c.js\""));
+        #[cfg(feature = "compress")]
+        {
+            assert!(code.contains(".brotli(include_bytes!("));
+            assert!(code.contains(".gzip(include_bytes!("));
+            assert!(root.join("assets.0.br").exists());
+            assert!(!root.join("assets.1.br").exists());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Entity tags.
+//!
+//! This module provides the [`ETag`] type for the entity tags of RFC 9110,
+//! section 8.8.3, and [`ETagList`] for the values of the If-Match and
+//! If-None-Match headers.
+
+use std::{error, fmt};
+use std::str::FromStr;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, IF_NONE_MATCH};
+use crate::request::Request;
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ ETag ----------------------------------------------------------
+
+/// An entity tag.
+///
+/// The tag is kept in its serialized form, i.e., with the quotes and, if
+/// it is weak, the `W/` prefix. This is also what [`AsRef<str>`] and
+/// [`Display`][fmt::Display] provide.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ETag {
+    value: String,
+}
+
+impl ETag {
+    /// Creates a strong entity tag from its opaque part.
+    ///
+    /// The opaque part is given without quotes. Returns an error if it
+    /// contains characters not allowed in entity tags.
+    pub fn strong(opaque: &str) -> Result<Self, InvalidETag> {
+        Self::check_opaque(opaque)?;
+        Ok(ETag { value: format!("\"{}\"", opaque) })
+    }
+
+    /// Creates a weak entity tag from its opaque part.
+    pub fn weak(opaque: &str) -> Result<Self, InvalidETag> {
+        Self::check_opaque(opaque)?;
+        Ok(ETag { value: format!("W/\"{}\"", opaque) })
+    }
+
+    /// Creates a strong entity tag from the content of a representation.
+    ///
+    /// The tag is a 64 bit FNV-1a hash of the content. This is fast and
+    /// stable but not collision resistant, so it should not be used if
+    /// the content is controlled by an adversary. Use
+    /// [`from_hash`][Self::from_hash] with a cryptographic hash instead.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for &byte in data {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        ETag { value: format!("\"{:016x}\"", hash) }
+    }
+
+    /// Creates a strong entity tag from a hash value.
+    ///
+    /// The tag consists of the hash in hex digits.
+    pub fn from_hash(hash: &[u8]) -> Self {
+        let mut value = String::with_capacity(hash.len() * 2 + 2);
+        value.push('"');
+        for byte in hash {
+            value.push_str(&format!("{:02x}", byte));
+        }
+        value.push('"');
+        ETag { value }
+    }
+
+    /// Creates a strong entity tag from a version counter.
+    pub fn from_version(version: u64) -> Self {
+        ETag { value: format!("\"{}\"", version) }
+    }
+
+    /// Parses an entity tag in its serialized form.
+    pub fn parse(value: &str) -> Result<Self, InvalidETag> {
+        let opaque = value.strip_prefix("W/").unwrap_or(value);
+        let opaque = opaque.strip_prefix('"').and_then(|opaque| {
+            opaque.strip_suffix('"')
+        }).ok_or(InvalidETag)?;
+        Self::check_opaque(opaque)?;
+        Ok(ETag { value: value.into() })
+    }
+
+    fn check_opaque(opaque: &str) -> Result<(), InvalidETag> {
+        if opaque.bytes().all(is_etagc) {
+            Ok(())
+        }
+        else {
+            Err(InvalidETag)
+        }
+    }
+
+    /// Returns whether the tag is weak.
+    pub fn is_weak(&self) -> bool {
+        self.value.starts_with("W/")
+    }
+
+    /// Returns the opaque part of the tag without the quotes.
+    pub fn opaque(&self) -> &str {
+        let start = if self.is_weak() { 3 } else { 1 };
+        &self.value[start..self.value.len() - 1]
+    }
+
+    /// Returns the weak version of the tag.
+    pub fn into_weak(self) -> Self {
+        if self.is_weak() {
+            self
+        }
+        else {
+            ETag { value: format!("W/{}", self.value) }
+        }
+    }
+
+    /// Returns the serialized tag.
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    /// Compares two tags using the strong comparison.
+    ///
+    /// The tags match if both are strong and their opaque parts are equal.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.is_weak() && !other.is_weak() && self.value == other.value
+    }
+
+    /// Compares two tags using the weak comparison.
+    ///
+    /// The tags match if their opaque parts are equal.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.opaque() == other.opaque()
+    }
+}
+
+impl FromStr for ETag {
+    type Err = InvalidETag;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl AsRef<str> for ETag {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<ETag> for HeaderValue {
+    fn from(etag: ETag) -> Self {
+        // The grammar check guarantees a valid header value.
+        HeaderValue::from_str(&etag.value).expect("invalid etag")
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+
+//------------ ETagList ------------------------------------------------------
+
+/// The value of an If-Match or If-None-Match header.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ETagList {
+    /// The value `*` matching any current representation.
+    Any,
+
+    /// A list of entity tags.
+    Tags(Vec<ETag>),
+}
+
+impl ETagList {
+    /// Parses the If-None-Match headers of a request.
+    ///
+    /// Returns `Ok(None)` if there is no such header and an error if any
+    /// of them is malformed.
+    pub fn if_none_match(
+        request: &Request
+    ) -> Result<Option<Self>, InvalidETag> {
+        Self::from_headers(request.headers(), &IF_NONE_MATCH)
+    }
+
+    /// Parses all headers with the given name.
+    ///
+    /// Multiple headers are combined into a single list. Returns
+    /// `Ok(None)` if there is no such header and an error if any of them
+    /// is malformed.
+    pub fn from_headers(
+        headers: &HeaderMap, name: &HeaderName
+    ) -> Result<Option<Self>, InvalidETag> {
+        let mut res: Option<Vec<ETag>> = None;
+        for value in headers.get_all(name) {
+            let value = value.to_str().map_err(|_| InvalidETag)?;
+            match Self::parse(value)? {
+                ETagList::Any => return Ok(Some(ETagList::Any)),
+                ETagList::Tags(tags) => {
+                    res.get_or_insert_with(Vec::new).extend(tags)
+                }
+            }
+        }
+        Ok(res.map(ETagList::Tags))
+    }
+
+    /// Parses a single header value.
+    ///
+    /// The value is either `*` or a comma-separated list of entity tags.
+    /// Empty list elements are permitted.
+    pub fn parse(value: &str) -> Result<Self, InvalidETag> {
+        let value = value.trim_matches([' ', '\t']);
+        if value == "*" {
+            return Ok(ETagList::Any)
+        }
+        let mut res = Vec::new();
+        let mut rest = value;
+        loop {
+            rest = rest.trim_start_matches([' ', '\t', ',']);
+            if rest.is_empty() {
+                return Ok(ETagList::Tags(res))
+            }
+            let quoted = rest.strip_prefix("W/").unwrap_or(rest);
+            let opaque = quoted.strip_prefix('"').ok_or(InvalidETag)?;
+            let end = opaque.find('"').ok_or(InvalidETag)?;
+            let len = rest.len() - opaque.len() + end + 1;
+            res.push(ETag::parse(&rest[..len])?);
+            rest = rest[len..].trim_start_matches([' ', '\t']);
+            if rest.is_empty() {
+                return Ok(ETagList::Tags(res))
+            }
+            rest = rest.strip_prefix(',').ok_or(InvalidETag)?;
+        }
+    }
+
+    /// Returns whether any tag matches using the strong comparison.
+    ///
+    /// `*` matches any tag.
+    pub fn matches_strong(&self, etag: &ETag) -> bool {
+        match self {
+            ETagList::Any => true,
+            ETagList::Tags(tags) => tags.iter().any(|tag| tag.strong_eq(etag))
+        }
+    }
+
+    /// Returns whether any tag matches using the weak comparison.
+    ///
+    /// `*` matches any tag.
+    pub fn matches_weak(&self, etag: &ETag) -> bool {
+        match self {
+            ETagList::Any => true,
+            ETagList::Tags(tags) => tags.iter().any(|tag| tag.weak_eq(etag))
+        }
+    }
+}
+
+
+//------------ InvalidETag ---------------------------------------------------
+
+/// An entity tag or a list of them was malformed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidETag;
+
+impl InvalidETag {
+    /// Converts the error into a Bad Request response.
+    pub fn into_response(self) -> Response {
+        ResponseBuilder::new().bad_request()
+            .content_type(ContentType::TEXT)
+            .body("Invalid entity tag.")
+    }
+}
+
+impl From<InvalidETag> for Response {
+    fn from(err: InvalidETag) -> Self {
+        err.into_response()
+    }
+}
+
+impl fmt::Display for InvalidETag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid entity tag")
+    }
+}
+
+impl error::Error for InvalidETag { }
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns whether a byte is allowed in the opaque part of a tag.
+///
+/// We don’t allow obs-text since header values containing it can’t be
+/// converted into strs.
+fn is_etagc(ch: u8) -> bool {
+    ch == 0x21 || (0x23..0x7f).contains(&ch)
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn etag() {
+        let strong = ETag::strong("foo").unwrap();
+        let weak = ETag::weak("foo").unwrap();
+        assert_eq!(strong.as_str(), "\"foo\"");
+        assert_eq!(weak.as_str(), "W/\"foo\"");
+        assert_eq!(weak.opaque(), "foo");
+        assert!(weak.is_weak());
+        assert_eq!(strong.clone().into_weak(), weak);
+        assert!(ETag::strong("fo\"o").is_err());
+        assert!(ETag::strong("fo o").is_err());
+
+        assert_eq!(ETag::parse("W/\"foo\"").unwrap(), weak);
+        assert!(ETag::parse("foo").is_err());
+        assert!(ETag::parse("\"foo").is_err());
+        assert!(ETag::parse("w/\"foo\"").is_err());
+
+        assert!(strong.strong_eq(&strong));
+        assert!(!weak.strong_eq(&weak));
+        assert!(weak.weak_eq(&strong));
+        assert!(!strong.weak_eq(&ETag::strong("bar").unwrap()));
+
+        assert_eq!(ETag::from_version(42).as_str(), "\"42\"");
+        assert_eq!(ETag::from_hash(&[0xde, 0xad]).as_str(), "\"dead\"");
+        assert_eq!(ETag::from_bytes(b"").as_str(), "\"cbf29ce484222325\"");
+        assert_ne!(ETag::from_bytes(b"a"), ETag::from_bytes(b"b"));
+    }
+
+    #[test]
+    fn etag_list() {
+        assert_eq!(ETagList::parse(" * ").unwrap(), ETagList::Any);
+        assert_eq!(
+            ETagList::parse("\"foo\", W/\"bar\" ,, \"ba,zz\", ").unwrap(),
+            ETagList::Tags(vec![
+                ETag::strong("foo").unwrap(),
+                ETag::weak("bar").unwrap(),
+                ETag::strong("ba,zz").unwrap(),
+            ])
+        );
+        assert_eq!(ETagList::parse("").unwrap(), ETagList::Tags(vec![]));
+        assert!(ETagList::parse("\"foo\" garbage").is_err());
+        assert!(ETagList::parse("foo").is_err());
+        assert!(ETagList::parse("\"foo").is_err());
+        assert!(ETagList::parse("\"foo\" \"bar\"").is_err());
+        assert!(ETagList::parse("\"foo\", *").is_err());
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            ETagList::from_headers(&headers, &IF_NONE_MATCH), Ok(None)
+        );
+        headers.append(IF_NONE_MATCH, HeaderValue::from_static("\"a\""));
+        headers.append(IF_NONE_MATCH, HeaderValue::from_static("W/\"b\""));
+        let list = ETagList::from_headers(
+            &headers, &IF_NONE_MATCH
+        ).unwrap().unwrap();
+        assert!(list.matches_weak(&ETag::strong("b").unwrap()));
+        assert!(!list.matches_strong(&ETag::strong("b").unwrap()));
+        assert!(list.matches_strong(&ETag::strong("a").unwrap()));
+        headers.append(IF_NONE_MATCH, HeaderValue::from_static("x"));
+        assert!(ETagList::from_headers(&headers, &IF_NONE_MATCH).is_err());
+    }
+}
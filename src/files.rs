@@ -0,0 +1,798 @@
+//! Serving static files.
+//!
+//! [`StaticFiles`] serves either the files in a directory or a table of
+//! assets compiled into the binary. It takes care of conditional
+//! requests, range requests, and index files.
+
+use std::io;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use hyper::{Body, Method};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, HeaderValue, VARY};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use crate::conditional::Preconditions;
+use crate::etag::ETag;
+use crate::negotiate::select_encoding;
+use crate::range::{RangeRequest, partial_response};
+use crate::request::Request;
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ StaticFiles ---------------------------------------------------
+
+/// A handler serving static files.
+///
+/// The files are either taken from a directory via [`dir`][Self::dir]
+/// or from a table of assets via [`assets`][Self::assets]. A request for
+/// a directory is answered with the first index file present in it. A
+/// request for a directory without a trailing slash is redirected to the
+/// path with the slash so that relative links in the index file work.
+///
+/// Paths containing `.` or `..` segments or characters that have a
+/// special meaning in file system paths are rejected with a 404 Not
+/// Found response. So are hidden files, i.e., paths with a segment
+/// starting with a dot, unless enabled via [`hidden`][Self::hidden].
+/// Symbolic links within the directory are followed.
+///
+/// Typically, the handler is used with a wildcard route:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use httools::files::StaticFiles;
+/// # use httools::router::Router;
+/// let router = Router::<StaticFiles>::new().get(
+///     "/static/*path",
+///     |files: Arc<StaticFiles>, request| async move {
+///         let path = request.params().get("path").unwrap_or("");
+///         files.serve_path(&request, path).await
+///     }
+/// );
+/// ```
+#[derive(Debug)]
+pub struct StaticFiles {
+    /// Where the files come from.
+    source: Source,
+
+    /// The names of the index files in order of preference.
+    index: Vec<String>,
+
+    /// Whether to serve paths with segments starting with a dot.
+    hidden: bool,
+}
+
+#[derive(Debug)]
+enum Source {
+    Dir(PathBuf),
+    Assets(HashMap<&'static str, AssetEntry>),
+}
+
+impl StaticFiles {
+    /// The maximum size of all ranges of a multipart response.
+    ///
+    /// A multipart response is assembled in memory, so if the requested
+    /// ranges are larger than this, the complete file is returned instead.
+    const MAX_MULTIPART_SIZE: u64 = 16 * 1024 * 1024;
+
+    /// The size of the chunks files are read in.
+    const CHUNK_SIZE: u64 = 64 * 1024;
+
+    /// Creates a handler serving the files in the given directory.
+    pub fn dir(root: impl Into<PathBuf>) -> Self {
+        Self::new(Source::Dir(root.into()))
+    }
+
+    /// Creates a handler serving the given assets.
+    ///
+    /// The entity tags of assets that don’t have one yet are calculated
+    /// here. If there are multiple assets with the same path, the last
+    /// one wins.
+    ///
+    /// A table of assets can be generated at compile time via
+    /// [`AssetTableBuilder`][crate::embed::AssetTableBuilder].
+    ///
+    /// # Panics
+    ///
+    /// The function panics if the entity tag given for an asset is
+    /// invalid.
+    pub fn assets(assets: impl IntoIterator<Item = Asset>) -> Self {
+        Self::new(Source::Assets(
+            assets.into_iter().map(|asset| {
+                (asset.path.trim_start_matches('/'), AssetEntry::new(asset))
+            }).collect()
+        ))
+    }
+
+    fn new(source: Source) -> Self {
+        StaticFiles {
+            source,
+            index: vec!["index.html".into()],
+            hidden: false,
+        }
+    }
+
+    /// Sets the names of the index files.
+    ///
+    /// The names are tried in the given order. The default is
+    /// `index.html`. If `names` is empty, requests for directories are
+    /// answered with 404 Not Found.
+    pub fn index(self, names: &[&str]) -> Self {
+        StaticFiles {
+            index: names.iter().map(|name| String::from(*name)).collect(),
+            ..self
+        }
+    }
+
+    /// Sets whether to serve hidden files.
+    ///
+    /// Hidden files are those with a path segment starting with a dot,
+    /// such as `.git/config` or `.well-known/security.txt`. They are not
+    /// served by default.
+    pub fn hidden(self, hidden: bool) -> Self {
+        StaticFiles { hidden, ..self }
+    }
+
+    /// Serves the file referred to by the complete request path.
+    pub async fn serve(
+        &self, request: &Request
+    ) -> Result<Response, Response> {
+        let path = request.path().map_err(|_| Response::bad_request())?;
+        self.serve_path(request, path.as_str()).await
+    }
+
+    /// Serves the file at the given path.
+    ///
+    /// The path is relative to the directory or asset table and must
+    /// already be percent-decoded, as the parameters of a
+    /// [`Router`][crate::router::Router] are.
+    pub async fn serve_path(
+        &self, request: &Request, path: &str
+    ) -> Result<Response, Response> {
+        request.require_get()?;
+        let segments = sanitize_path(
+            path, self.hidden
+        ).ok_or_else(Response::not_found)?;
+        let is_dir = path.is_empty() || path.ends_with('/');
+        match self.source {
+            Source::Dir(ref root) => {
+                self.serve_dir(request, root, &segments, is_dir).await
+            }
+            Source::Assets(ref assets) => {
+                self.serve_asset(request, assets, &segments, is_dir)
+            }
+        }
+    }
+
+    async fn serve_dir(
+        &self, request: &Request, root: &Path, segments: &[&str],
+        is_dir: bool,
+    ) -> Result<Response, Response> {
+        let mut path = root.to_path_buf();
+        path.extend(segments);
+        let meta = tokio::fs::metadata(&path).await.map_err(io_response)?;
+        if meta.is_file() {
+            if is_dir {
+                return Err(Response::not_found())
+            }
+            return self.serve_file(request, &path, meta).await
+        }
+        if !meta.is_dir() {
+            return Err(Response::not_found())
+        }
+        for index in &self.index {
+            let path = path.join(index);
+            if let Ok(meta) = tokio::fs::metadata(&path).await {
+                if meta.is_file() {
+                    if !is_dir {
+                        return Err(redirect_to_dir(request))
+                    }
+                    return self.serve_file(request, &path, meta).await
+                }
+            }
+        }
+        Err(Response::not_found())
+    }
+
+    async fn serve_file(
+        &self, request: &Request, path: &Path, meta: std::fs::Metadata,
+    ) -> Result<Response, Response> {
+        let len = meta.len();
+        let modified = meta.modified().ok();
+        let etag = file_etag(len, modified);
+        let cond = Preconditions::new().etag(etag.clone());
+        let builder = ResponseBuilder::new().etag(&etag);
+        #[cfg(feature = "chrono")]
+        let (cond, builder) = match modified {
+            Some(modified) => {
+                let modified = DateTime::<Utc>::from(modified);
+                (
+                    cond.last_modified(modified),
+                    builder.last_modified(modified)
+                )
+            }
+            None => (cond, builder)
+        };
+        cond.evaluate(request)?;
+
+        let content_type = path.file_name().and_then(|name| {
+            name.to_str()
+        }).map(guess_content_type).unwrap_or(ContentType::OCTET_STREAM);
+        if request.method() == Method::HEAD {
+            return Ok(
+                builder.ok().accept_ranges()
+                    .content_type(content_type)
+                    .header(CONTENT_LENGTH, HeaderValue::from(len))
+                    .empty()
+            )
+        }
+        let mut file = File::open(path).await.map_err(io_response)?;
+        let ranges = match RangeRequest::evaluate(request, &cond, len) {
+            RangeRequest::Full => Vec::new(),
+            RangeRequest::Partial(ranges) => ranges,
+            RangeRequest::Unsatisfiable => {
+                return Err(Response::range_not_satisfiable(len))
+            }
+        };
+
+        if let [range] = ranges.as_slice() {
+            file.seek(io::SeekFrom::Start(range.start)).await.map_err(
+                io_response
+            )?;
+            let part_len = range.end - range.start;
+            return Ok(
+                builder.partial_content().accept_ranges()
+                    .content_type(content_type)
+                    .content_range(range.clone(), len)
+                    .header(CONTENT_LENGTH, HeaderValue::from(part_len))
+                    .body(file_body(file, part_len))
+            )
+        }
+        let total: u64 = ranges.iter().map(|range| {
+            range.end - range.start
+        }).sum();
+        if !ranges.is_empty() && total <= Self::MAX_MULTIPART_SIZE {
+            let mut parts = Vec::with_capacity(ranges.len());
+            for range in ranges {
+                let data = read_range(
+                    &mut file, range.clone()
+                ).await.map_err(io_response)?;
+                parts.push((range, data));
+            }
+            return Ok(partial_response(builder, content_type, len, parts))
+        }
+        Ok(
+            builder.ok().accept_ranges()
+                .content_type(content_type)
+                .header(CONTENT_LENGTH, HeaderValue::from(len))
+                .body(file_body(file, len))
+        )
+    }
+
+    fn serve_asset(
+        &self, request: &Request, assets: &HashMap<&str, AssetEntry>,
+        segments: &[&str], is_dir: bool,
+    ) -> Result<Response, Response> {
+        let path = segments.join("/");
+        if !is_dir {
+            if let Some(asset) = assets.get(path.as_str()) {
+                return asset.respond(request)
+            }
+        }
+        for index in &self.index {
+            let index_path = if path.is_empty() {
+                index.clone()
+            }
+            else {
+                format!("{}/{}", path, index)
+            };
+            if let Some(asset) = assets.get(index_path.as_str()) {
+                if !is_dir {
+                    return Err(redirect_to_dir(request))
+                }
+                return asset.respond(request)
+            }
+        }
+        Err(Response::not_found())
+    }
+}
+
+
+//------------ Asset ---------------------------------------------------------
+
+/// A file compiled into the binary.
+///
+/// The content type of the asset is derived from the extension of its
+/// path.
+///
+/// An asset can have pre-compressed variants of its content. If so, the
+/// variant is selected via the Accept-Encoding header of a request. Each
+/// variant has its own entity tag derived from that of the asset.
+#[derive(Clone, Copy, Debug)]
+pub struct Asset {
+    path: &'static str,
+    content: &'static [u8],
+    etag: Option<&'static str>,
+    brotli: Option<&'static [u8]>,
+    gzip: Option<&'static [u8]>,
+}
+
+impl Asset {
+    /// Creates a new asset.
+    ///
+    /// The path is relative to the root of the asset table and uses
+    /// slashes as separators. Typically, the content is provided via
+    /// `include_bytes!`.
+    pub const fn new(path: &'static str, content: &'static [u8]) -> Self {
+        Asset { path, content, etag: None, brotli: None, gzip: None }
+    }
+
+    /// Sets the entity tag of the asset.
+    ///
+    /// The tag is given in its serialized form, including the quotes. If
+    /// no tag is set, it is derived from the content.
+    pub const fn etag(self, etag: &'static str) -> Self {
+        Asset { etag: Some(etag), ..self }
+    }
+
+    /// Adds the Brotli-compressed variant of the content.
+    pub const fn brotli(self, data: &'static [u8]) -> Self {
+        Asset { brotli: Some(data), ..self }
+    }
+
+    /// Adds the gzip-compressed variant of the content.
+    pub const fn gzip(self, data: &'static [u8]) -> Self {
+        Asset { gzip: Some(data), ..self }
+    }
+}
+
+
+//------------ AssetEntry ----------------------------------------------------
+
+/// An asset prepared for serving.
+#[derive(Debug)]
+struct AssetEntry {
+    content: Bytes,
+    etag: ETag,
+    content_type: ContentType,
+
+    /// The content codings of the compressed variants.
+    codings: Vec<&'static str>,
+
+    /// The compressed variants in the same order as `codings`.
+    variants: Vec<(Bytes, ETag)>,
+}
+
+impl AssetEntry {
+    fn new(asset: Asset) -> Self {
+        let etag = match asset.etag {
+            Some(etag) => ETag::parse(etag).expect("invalid entity tag"),
+            None => ETag::from_bytes(asset.content),
+        };
+        let mut codings = Vec::new();
+        let mut variants = Vec::new();
+        for (coding, data) in [("br", asset.brotli), ("gzip", asset.gzip)] {
+            if let Some(data) = data {
+                codings.push(coding);
+                variants.push((
+                    Bytes::from_static(data), variant_etag(&etag, coding)
+                ));
+            }
+        }
+        AssetEntry {
+            content: Bytes::from_static(asset.content),
+            etag,
+            content_type: guess_content_type(asset.path),
+            codings, variants,
+        }
+    }
+
+    fn respond(&self, request: &Request) -> Result<Response, Response> {
+        let selected = select_encoding(request.headers(), &self.codings);
+        let (content, etag) = match selected {
+            Some(idx) => (&self.variants[idx].0, &self.variants[idx].1),
+            None => (&self.content, &self.etag),
+        };
+        let vary = !self.codings.is_empty();
+
+        let cond = Preconditions::new().etag(etag.clone());
+        cond.evaluate(request).map_err(|response| {
+            if vary {
+                let mut response = response.into_hyper();
+                response.headers_mut().append(
+                    VARY, HeaderValue::from_static("Accept-Encoding")
+                );
+                Response::from_hyper(response)
+            }
+            else {
+                response
+            }
+        })?;
+        let mut builder = ResponseBuilder::new().etag(etag);
+        if vary {
+            builder = builder.vary("Accept-Encoding");
+        }
+        if let Some(idx) = selected {
+            builder = builder.header(
+                CONTENT_ENCODING, HeaderValue::from_static(self.codings[idx])
+            );
+        }
+        let len = content.len() as u64;
+        Ok(RangeRequest::evaluate(request, &cond, len).respond(
+            builder, self.content_type.clone(), content.clone(),
+        ))
+    }
+}
+
+/// Creates the entity tag of a compressed variant.
+fn variant_etag(etag: &ETag, coding: &str) -> ETag {
+    let opaque = format!("{}-{}", etag.opaque(), coding);
+    if etag.is_weak() {
+        ETag::weak(&opaque)
+    }
+    else {
+        ETag::strong(&opaque)
+    }.expect("invalid entity tag")
+}
+
+
+//------------ guess_content_type --------------------------------------------
+
+/// Guesses the content type of a file from the extension of its name.
+///
+/// Unknown extensions result in `application/octet-stream`.
+fn guess_content_type(name: &str) -> ContentType {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) => ext.to_ascii_lowercase(),
+        None => return ContentType::OCTET_STREAM,
+    };
+    match ext.as_str() {
+        "css" => ContentType::CSS,
+        "csv" => ContentType::CSV,
+        "htm" | "html" => ContentType::HTML,
+        "js" | "mjs" => ContentType::JS,
+        "json" | "map" => ContentType::JSON,
+        "svg" => ContentType::SVG,
+        "txt" => ContentType::TEXT,
+        "xml" => ContentType::external("application/xml"),
+        "wasm" => ContentType::external("application/wasm"),
+        "pdf" => ContentType::external("application/pdf"),
+        "gif" => ContentType::external("image/gif"),
+        "ico" => ContentType::external("image/vnd.microsoft.icon"),
+        "jpeg" | "jpg" => ContentType::external("image/jpeg"),
+        "png" => ContentType::external("image/png"),
+        "webp" => ContentType::external("image/webp"),
+        "otf" => ContentType::external("font/otf"),
+        "ttf" => ContentType::external("font/ttf"),
+        "woff" => ContentType::external("font/woff"),
+        "woff2" => ContentType::external("font/woff2"),
+        "gz" => ContentType::external("application/gzip"),
+        "zip" => ContentType::external("application/zip"),
+        _ => ContentType::OCTET_STREAM,
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Splits a request path into segments safe to use in a file path.
+///
+/// Empty segments are skipped. Returns `None` if any segment is `.` or
+/// `..` or contains a backslash, colon, or NUL character. Unless
+/// `hidden` is true, segments starting with a dot are rejected, too.
+fn sanitize_path(path: &str, hidden: bool) -> Option<Vec<&str>> {
+    let mut res = Vec::new();
+    for segment in path.split('/') {
+        if segment.is_empty() {
+            continue
+        }
+        if segment == "." || segment == ".."
+            || (!hidden && segment.starts_with('.'))
+            || segment.contains(['\\', ':', '\0'])
+        {
+            return None
+        }
+        res.push(segment)
+    }
+    Some(res)
+}
+
+/// Returns the redirect to the request path with a trailing slash added.
+///
+/// Leading slashes are collapsed into one so that the location can’t be
+/// taken for a protocol-relative URL pointing to another host.
+fn redirect_to_dir(request: &Request) -> Response {
+    let mut location = format!(
+        "/{}/", request.uri().path().trim_start_matches(['/', '\\'])
+    );
+    if let Some(query) = request.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Response::moved_permanently(&location)
+}
+
+/// Creates the entity tag for a file.
+///
+/// The tag is derived from the modification time and size of the file.
+/// If the modification time isn’t available, a weak tag is used.
+fn file_etag(len: u64, modified: Option<SystemTime>) -> ETag {
+    match modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(time) => {
+            ETag::strong(&format!("{:x}-{:x}", time.as_nanos(), len))
+        }
+        None => ETag::weak(&format!("{:x}", len))
+    }.expect("invalid entity tag")
+}
+
+/// Creates a body streaming `len` bytes from the file.
+fn file_body(file: File, len: u64) -> Body {
+    Body::wrap_stream(stream::unfold((file, len), |(mut file, len)| {
+        async move {
+            if len == 0 {
+                return None
+            }
+            let mut buf = vec![0; len.min(StaticFiles::CHUNK_SIZE) as usize];
+            match file.read(&mut buf).await {
+                Ok(0) => Some((
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof, "file truncated"
+                    )),
+                    (file, 0)
+                )),
+                Ok(read) => {
+                    buf.truncate(read);
+                    Some((Ok(Bytes::from(buf)), (file, len - read as u64)))
+                }
+                Err(err) => Some((Err(err), (file, 0)))
+            }
+        }
+    }))
+}
+
+/// Reads the given range of a file.
+async fn read_range(
+    file: &mut File, range: Range<u64>
+) -> Result<Bytes, io::Error> {
+    file.seek(io::SeekFrom::Start(range.start)).await?;
+    let mut buf = vec![0; (range.end - range.start) as usize];
+    file.read_exact(&mut buf).await?;
+    Ok(buf.into())
+}
+
+/// Converts an IO error into a response.
+///
+/// Errors indicating that the file doesn’t exist or can’t be accessed
+/// result in 404 Not Found, everything else in 500 Internal Server Error.
+fn io_response(err: io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+        | io::ErrorKind::PermissionDenied => Response::not_found(),
+        _ => Response::internal_server_error(),
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::StatusCode;
+
+    async fn get(
+        files: &StaticFiles, path: &str, headers: &[(&str, &str)]
+    ) -> (StatusCode, hyper::HeaderMap, String) {
+        let mut request = hyper::Request::builder().uri(path);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = Request::from_hyper(
+            request.body(Body::empty()).unwrap()
+        );
+        let response = match files.serve(&request).await {
+            Ok(response) | Err(response) => response.into_hyper()
+        };
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (
+            parts.status, parts.headers,
+            String::from_utf8(body.into()).unwrap()
+        )
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(sanitize_path("", false), Some(vec![]));
+        assert_eq!(sanitize_path("/a//b/", false), Some(vec!["a", "b"]));
+        assert_eq!(sanitize_path(".well-known/x", false), None);
+        assert_eq!(sanitize_path("a/.git/config", false), None);
+        assert_eq!(
+            sanitize_path(".well-known/x", true),
+            Some(vec![".well-known", "x"])
+        );
+        assert_eq!(sanitize_path("a/../b", true), None);
+        assert_eq!(sanitize_path("./a", true), None);
+        assert_eq!(sanitize_path("a\\..\\b", false), None);
+        assert_eq!(sanitize_path("C:/windows", false), None);
+        assert_eq!(sanitize_path("a\0b", false), None);
+    }
+
+    #[test]
+    fn content_type() {
+        assert_eq!(guess_content_type("index.HTML").as_str(),
+            ContentType::HTML.as_str()
+        );
+        assert_eq!(guess_content_type("a.tar.gz").as_str(),
+            "application/gzip"
+        );
+        assert_eq!(guess_content_type("README").as_str(),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn assets() {
+        let files = StaticFiles::assets([
+            Asset::new("index.html", b"<p>root</p>"),
+            Asset::new("/docs/index.html", b"<p>docs</p>"),
+            Asset::new("docs/style.css", b"p { color: red }"),
+            Asset::new("docs/.hidden", b"hidden"),
+        ]);
+
+        let (status, headers, body) = get(&files, "/", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["Content-Type"], ContentType::HTML.as_str());
+        assert_eq!(body, "<p>root</p>");
+
+        let (status, headers, _) = get(&files, "/docs?x=1", &[]).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers["Location"], "/docs/?x=1");
+
+        let (status, headers, body) = get(
+            &files, "/docs/style.css", &[("Range", "bytes=4-8")]
+        ).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers["Content-Type"], "text/css");
+        assert_eq!(body, "color");
+
+        let etag = headers["ETag"].to_str().unwrap().to_string();
+        let (status, _, _) = get(
+            &files, "/docs/style.css", &[("If-None-Match", &etag)]
+        ).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let (status, _, _) = get(&files, "/docs/style.css/", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/docs/.hidden", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/docs/../index.html", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/missing", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn asset_variants() {
+        let files = StaticFiles::assets([
+            Asset::new("index.html", b"<p>root</p>").etag("\"i1\""),
+            Asset::new("style.css", b"p { color: red }")
+                .etag("\"s1\"").brotli(b"brotli").gzip(b"gzip"),
+        ]);
+
+        let (_, headers, _) = get(&files, "/", &[]).await;
+        assert_eq!(headers["ETag"], "\"i1\"");
+        assert!(!headers.contains_key("Vary"));
+
+        let (_, headers, body) = get(&files, "/style.css", &[]).await;
+        assert_eq!(headers["Vary"], "Accept-Encoding");
+        assert_eq!(headers["ETag"], "\"s1\"");
+        assert!(!headers.contains_key("Content-Encoding"));
+        assert_eq!(body, "p { color: red }");
+
+        let (_, headers, body) = get(
+            &files, "/style.css", &[("Accept-Encoding", "gzip, br")]
+        ).await;
+        assert_eq!(headers["Content-Encoding"], "br");
+        assert_eq!(headers["ETag"], "\"s1-br\"");
+        assert_eq!(body, "brotli");
+
+        let (status, headers, _) = get(
+            &files, "/style.css",
+            &[("Accept-Encoding", "gzip"), ("If-None-Match", "\"s1-gzip\"")]
+        ).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers["Vary"], "Accept-Encoding");
+        assert_eq!(headers["ETag"], "\"s1-gzip\"");
+
+        let (status, _, body) = get(
+            &files, "/style.css", &[("Range", "bytes=4-8")]
+        ).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, "color");
+    }
+
+    #[tokio::test]
+    async fn dir() {
+        let root = std::env::temp_dir().join(format!(
+            "httools-files-test-{}", std::process::id()
+        ));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("data.txt"), "0123456789").unwrap();
+        std::fs::write(root.join("sub/index.html"), "<p>sub</p>").unwrap();
+        std::fs::write(root.join(".secret"), "secret").unwrap();
+        let files = StaticFiles::dir(&root);
+
+        let (status, headers, body) = get(&files, "/data.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["Content-Type"], ContentType::TEXT.as_str());
+        assert_eq!(headers["Content-Length"], "10");
+        assert_eq!(body, "0123456789");
+
+        let etag = headers["ETag"].to_str().unwrap().to_string();
+        let (status, _, _) = get(
+            &files, "/data.txt", &[("If-None-Match", &etag)]
+        ).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let request = Request::from_hyper(
+            hyper::Request::builder().method(Method::HEAD).uri("/data.txt")
+                .body(Body::empty()).unwrap()
+        );
+        let response = files.serve(&request).await.unwrap().into_hyper();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Length"], "10");
+        assert_eq!(response.headers()["ETag"], etag.as_str());
+
+        let (status, headers, body) = get(
+            &files, "/data.txt", &[("Range", "bytes=-3")]
+        ).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers["Content-Range"], "bytes 7-9/10");
+        assert_eq!(body, "789");
+
+        let (status, headers, body) = get(
+            &files, "/data.txt", &[("Range", "bytes=0-0,-1")]
+        ).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert!(
+            headers["Content-Type"].to_str().unwrap().starts_with(
+                "multipart/byteranges"
+            )
+        );
+        assert!(body.contains("\r\n0\r\n") && body.contains("\r\n9\r\n"));
+
+        let (status, _, _) = get(
+            &files, "/data.txt", &[("Range", "bytes=10-")]
+        ).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let (status, headers, _) = get(&files, "/sub", &[]).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers["Location"], "/sub/");
+        let (status, headers, _) = get(&files, "//sub", &[]).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers["Location"], "/sub/");
+        let (_, _, body) = get(&files, "/sub/", &[]).await;
+        assert_eq!(body, "<p>sub</p>");
+
+        let (status, _, _) = get(&files, "/", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/sub/%2e%2e/data.txt", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/data.txt/x", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(&files, "/.secret", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let files = files.hidden(true);
+        let (_, _, body) = get(&files, "/.secret", &[]).await;
+        assert_eq!(body, "secret");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
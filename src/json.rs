@@ -1,7 +1,15 @@
 //! Building JSON on the fly.
 #![cfg(feature = "json")]
 
-use std::fmt;
+use std::{error, fmt, io, mem, str};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use futures_util::stream;
+use hyper::Body;
+use hyper::body::Bytes;
+use serde::Serialize;
+use tokio::sync::{Semaphore, mpsc};
 use crate::response::{ContentType, Response};
 
 
@@ -22,16 +30,28 @@ impl<F: Fn(&mut JsonValue)> BuildJson for F {
 
 /// A helper type for building a JSON-encoded string on the fly.
 ///
-/// Note that the builder only supports strings without control characters.
+/// Keys and string values are escaped via [`json_str`] or, if enabled
+/// via [`set_html_safe`][Self::set_html_safe], [`json_str_html`].
 pub struct JsonBuilder {
-    target: String,
+    target: JsonTarget,
 }
 
 impl JsonBuilder {
+    /// The number of chunks buffered by a streaming builder.
+    const STREAM_CHUNKS: usize = 4;
+
+    /// The maximum number of streaming builders running at the same time.
+    pub const MAX_STREAMS: usize = 64;
+
+    /// How long a streaming builder waits for the client to take a chunk.
+    pub const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
     pub fn build<F: FnOnce(&mut JsonBuilder)>(op: F) -> String {
-        let mut builder = JsonBuilder { target: String::new() };
+        let mut builder = JsonBuilder {
+            target: JsonTarget::string(JsonFormat::default())
+        };
         op(&mut builder);
-        builder.target
+        builder.target.buf
     }
 
     pub fn ok<F: FnOnce(&mut JsonBuilder)>(op: F) -> Response {
@@ -41,13 +61,142 @@ impl JsonBuilder {
     pub fn ok_object<F: FnOnce(&mut JsonObject)>(op: F) -> Response {
         Response::ok(ContentType::JSON, Self::build(|json| json.object(op)))
     }
+
+    /// Returns a response with the serialized value as its body.
+    ///
+    /// If serialization fails, returns 500 Internal Server Error.
+    pub fn ok_serialize(value: &impl Serialize) -> Response {
+        let mut res = Ok(());
+        let body = Self::build(|json| {
+            json.value(|json| res = json.serialize(value))
+        });
+        match res {
+            Ok(()) => Response::ok(ContentType::JSON, body),
+            Err(_) => Response::internal_server_error(),
+        }
+    }
+
+    /// Builds JSON into a streaming body.
+    ///
+    /// The closure is run on a blocking thread of the Tokio runtime and
+    /// its output is sent to the body in chunks through a bounded channel.
+    /// If the channel is full, the closure blocks until the client has
+    /// caught up, so memory use stays flat regardless of the size of the
+    /// output.
+    ///
+    /// Because each running stream occupies one of the runtime’s blocking
+    /// threads for as long as the client takes to read it, at most
+    /// [`MAX_STREAMS`][Self::MAX_STREAMS] streams run at the same time.
+    /// Further streams wait for a running one to finish before the
+    /// closure is started. If the client doesn’t take a chunk within
+    /// [`STREAM_TIMEOUT`][Self::STREAM_TIMEOUT], it is treated as gone.
+    /// Keep this in mind when sizing the blocking thread pool: other
+    /// users, such as `tokio::fs`, share it.
+    ///
+    /// If the client goes away, all further output is discarded. The
+    /// closure can check [`is_closed`][Self::is_closed] to stop early.
+    /// If the closure panics or the client times out, the body ends with
+    /// an error so the client can tell the output is truncated.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if called outside of a Tokio runtime. The
+    /// runtime needs to have the time driver enabled.
+    pub fn stream<F>(op: F) -> Body
+    where F: FnOnce(&mut JsonBuilder) + Send + 'static {
+        static STREAMS: Semaphore = Semaphore::const_new(
+            JsonBuilder::MAX_STREAMS
+        );
+
+        let (tx, rx) = mpsc::channel(Self::STREAM_CHUNKS);
+        let complete = Arc::new(AtomicBool::new(false));
+        let builder_complete = complete.clone();
+        tokio::spawn(async move {
+            // The semaphore is never closed, so this never fails.
+            let Ok(permit) = STREAMS.acquire().await else { return };
+            if tx.is_closed() {
+                return
+            }
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let mut builder = JsonBuilder {
+                    target: JsonTarget::channel(tx)
+                };
+                op(&mut builder);
+                builder.target.flush();
+                if !builder.target.is_closed() {
+                    builder_complete.store(true, Ordering::Release);
+                }
+            });
+        });
+        Body::wrap_stream(stream::unfold(Some(rx), move |rx| {
+            let complete = complete.clone();
+            async move {
+                let mut rx = rx?;
+                match rx.recv().await {
+                    Some(chunk) => Some((Ok(chunk), Some(rx))),
+                    // The sender is dropped after setting the flag, so
+                    // we see the flag once the channel has closed.
+                    None if complete.load(Ordering::Acquire) => None,
+                    None => Some((
+                        Err(io::Error::other("JSON output incomplete")),
+                        None
+                    )),
+                }
+            }
+        }))
+    }
+
+    /// Returns a response with a streaming JSON body.
+    ///
+    /// See [`stream`][Self::stream] for details.
+    pub fn ok_stream<F>(op: F) -> Response
+    where F: FnOnce(&mut JsonBuilder) + Send + 'static {
+        Response::ok(ContentType::JSON, Self::stream(op))
+    }
+
+    /// Returns a response with a streaming JSON object as its body.
+    ///
+    /// See [`stream`][Self::stream] for details.
+    pub fn ok_stream_object<F>(op: F) -> Response
+    where F: FnOnce(&mut JsonObject) + Send + 'static {
+        Response::ok(
+            ContentType::JSON, Self::stream(|json| json.object(op))
+        )
+    }
 }
 
 impl JsonBuilder {
+    /// Sets the output format.
+    ///
+    /// This only affects output written after the call, so it should be
+    /// called before writing anything.
+    pub fn set_format(&mut self, format: JsonFormat) {
+        self.target.format = format
+    }
+
+    /// Sets whether the output is escaped for embedding in HTML.
+    ///
+    /// If enabled, all keys and strings, including those written via
+    /// `serialize`, are escaped as by [`json_str_html`]. Values written
+    /// via `raw` are not. Like [`set_format`][Self::set_format], this
+    /// only affects output written after the call.
+    pub fn set_html_safe(&mut self, html_safe: bool) {
+        self.target.html = html_safe
+    }
+
+    /// Returns whether the receiver of a streaming builder has gone away.
+    ///
+    /// All output is discarded from then on. A builder that doesn’t
+    /// stream is never closed.
+    pub fn is_closed(&self) -> bool {
+        self.target.is_closed()
+    }
+
     pub fn value(&mut self, op: impl FnOnce(&mut JsonValue)) {
         op(&mut JsonValue {
             target: &mut self.target,
-            indent: 1,
+            indent: 0,
         });
     }
 
@@ -98,15 +247,38 @@ impl JsonBuilder {
         self.value(|json| json.raw(value));
         //write!(self.target, "{}", json_str(value));
     }
+
+    pub fn number(&mut self, value: impl JsonNumber) {
+        self.value(|json| json.number(value));
+    }
+
+    pub fn try_number(
+        &mut self, value: impl JsonNumber
+    ) -> Result<(), NonFiniteNumber> {
+        NonFiniteNumber::check(&value)?;
+        self.number(value);
+        Ok(())
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.value(|json| json.bool(value));
+    }
+
+    pub fn null(&mut self) {
+        self.value(|json| json.null());
+    }
 }
 
 
 //------------ JsonObject ---------------------------------------------------
 
 pub struct JsonObject<'a> {
-    target: &'a mut String,
+    target: &'a mut JsonTarget,
     indent: usize,
     empty: bool,
+
+    /// The members collected for sorting in canonical format.
+    members: Option<Vec<(String, String)>>,
 }
     
 impl<'a> JsonObject<'a> {
@@ -115,10 +287,16 @@ impl<'a> JsonObject<'a> {
         key: impl fmt::Display,
         op: impl FnOnce(&mut JsonValue)
     ) {
+        if let Some(members) = self.members.as_mut() {
+            let mut target = self.target.nested();
+            op(&mut JsonValue { target: &mut target, indent: 0 });
+            members.push((key.to_string(), target.buf));
+            return
+        }
         self.append_key(key);
         op(&mut JsonValue {
             target: self.target,
-            indent: self.indent + 1,
+            indent: self.indent,
         });
     }
 
@@ -180,24 +358,92 @@ impl<'a> JsonObject<'a> {
         */
     }
 
+    pub fn number(
+        &mut self, key: impl fmt::Display, value: impl JsonNumber
+    ) {
+        self.value(key, |json| json.number(value))
+    }
+
+    /// Writes a number member unless the number is not finite.
+    ///
+    /// Nothing is written, not even the key, if an error is returned.
+    pub fn try_number(
+        &mut self, key: impl fmt::Display, value: impl JsonNumber
+    ) -> Result<(), NonFiniteNumber> {
+        NonFiniteNumber::check(&value)?;
+        self.number(key, value);
+        Ok(())
+    }
+
+    pub fn bool(&mut self, key: impl fmt::Display, value: bool) {
+        self.value(key, |json| json.bool(value))
+    }
+
+    pub fn null(&mut self, key: impl fmt::Display) {
+        self.value(key, |json| json.null())
+    }
+
+    pub fn optional<T>(
+        &mut self,
+        key: impl fmt::Display,
+        value: Option<T>,
+        op: impl FnOnce(&mut JsonValue, T),
+    ) {
+        self.value(key, |json| json.optional(value, op))
+    }
+
+    /// Writes a member via the value’s `Serialize` implementation.
+    ///
+    /// See [`JsonValue::serialize`] for details.
+    pub fn serialize(
+        &mut self, key: impl fmt::Display, value: &impl Serialize
+    ) -> Result<(), serde_json::Error> {
+        let mut res = Ok(());
+        self.value(key, |json| res = json.serialize(value));
+        res
+    }
+
     fn append_key(&mut self, key: impl fmt::Display) {
         if self.empty {
             self.empty = false
         }
         else {
-            self.target.push_str(",\n");
+            self.target.push(',');
+            self.target.maybe_flush();
         }
-        self.append_indent();
+        self.target.newline(self.indent);
         self.target.push('"');
-        write!(self.target, "{}", json_str(key));
+        let html = self.target.html;
+        write!(self.target, "{}", JsonStr { value: key, html });
         self.target.push('"');
-        self.target.push_str(": ");
+        self.target.push(':');
+        if self.target.format.is_pretty() {
+            self.target.push(' ');
+        }
+    }
+
+    /// Returns whether the receiver of a streaming builder has gone away.
+    ///
+    /// See [`JsonBuilder::is_closed`] for details.
+    pub fn is_closed(&self) -> bool {
+        self.target.is_closed()
     }
 
-    fn append_indent(&mut self) {
-        for _ in 0..self.indent {
-            self.target.push_str("   ");
+    /// Writes the collected members and closes the object.
+    fn finish(mut self) {
+        if let Some(mut members) = self.members.take() {
+            members.sort_by(|(left, _), (right, _)| {
+                left.encode_utf16().cmp(right.encode_utf16())
+            });
+            for (key, value) in members {
+                self.append_key(key);
+                self.target.push_str(&value);
+            }
+        }
+        if !self.empty {
+            self.target.newline(self.indent - 1);
         }
+        self.target.push('}');
     }
 }
 
@@ -205,7 +451,7 @@ impl<'a> JsonObject<'a> {
 //------------ JsonArray ----------------------------------------------------
 
 pub struct JsonArray<'a> {
-    target: &'a mut String,
+    target: &'a mut JsonTarget,
     indent: usize,
     empty: bool,
 }
@@ -213,10 +459,10 @@ pub struct JsonArray<'a> {
 impl<'a> JsonArray<'a> {
     pub fn value(&mut self, op: impl FnOnce(&mut JsonValue)) {
         self.append_array_head();
-        self.append_indent();
+        self.target.newline(self.indent);
         op(&mut JsonValue {
             target: self.target,
-            indent: self.indent + 1,
+            indent: self.indent,
         })
     }
 
@@ -274,19 +520,69 @@ impl<'a> JsonArray<'a> {
         */
     }
 
+    pub fn number(&mut self, value: impl JsonNumber) {
+        self.value(|json| json.number(value))
+    }
+
+    /// Writes a number element unless the number is not finite.
+    ///
+    /// Nothing is written if an error is returned.
+    pub fn try_number(
+        &mut self, value: impl JsonNumber
+    ) -> Result<(), NonFiniteNumber> {
+        NonFiniteNumber::check(&value)?;
+        self.number(value);
+        Ok(())
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.value(|json| json.bool(value))
+    }
+
+    pub fn null(&mut self) {
+        self.value(|json| json.null())
+    }
+
+    pub fn optional<T>(
+        &mut self, value: Option<T>, op: impl FnOnce(&mut JsonValue, T)
+    ) {
+        self.value(|json| json.optional(value, op))
+    }
+
+    /// Writes an element via the value’s `Serialize` implementation.
+    ///
+    /// See [`JsonValue::serialize`] for details.
+    pub fn serialize(
+        &mut self, value: &impl Serialize
+    ) -> Result<(), serde_json::Error> {
+        let mut res = Ok(());
+        self.value(|json| res = json.serialize(value));
+        res
+    }
+
+    /// Returns whether the receiver of a streaming builder has gone away.
+    ///
+    /// See [`JsonBuilder::is_closed`] for details.
+    pub fn is_closed(&self) -> bool {
+        self.target.is_closed()
+    }
+
     fn append_array_head(&mut self) {
         if self.empty {
             self.empty = false
         }
         else {
-            self.target.push_str(",\n");
+            self.target.push(',');
+            self.target.maybe_flush();
         }
     }
 
-    fn append_indent(&mut self) {
-        for _ in 0..self.indent {
-            self.target.push_str("   ");
+    /// Closes the array.
+    fn finish(self) {
+        if !self.empty {
+            self.target.newline(self.indent - 1);
         }
+        self.target.push(']');
     }
 }
 
@@ -294,81 +590,719 @@ impl<'a> JsonArray<'a> {
 //------------ JsonValue ----------------------------------------------------
 
 pub struct JsonValue<'a> {
-    target: &'a mut String,
+    target: &'a mut JsonTarget,
     indent: usize,
 }
 
 impl<'a> JsonValue<'a> {
     pub fn object<F: FnOnce(&mut JsonObject)>(&mut self, op: F) {
-        self.target.push_str("{\n");
-        op(&mut JsonObject {
+        self.target.push('{');
+        let members = match self.target.format {
+            JsonFormat::Canonical => Some(Vec::new()),
+            _ => None,
+        };
+        let mut object = JsonObject {
             target: self.target,
             indent: self.indent + 1,
-            empty: true
-        });
-        self.target.push('\n');
-        self.append_indent();
-        self.target.push('}');
+            empty: true,
+            members,
+        };
+        op(&mut object);
+        object.finish();
     }
 
     pub fn array<F: FnOnce(&mut JsonArray)>(&mut self, op: F) {
-        self.target.push_str("[\n");
-        op(&mut JsonArray {
+        self.target.push('[');
+        let mut array = JsonArray {
             target: self.target,
             indent: self.indent + 1,
             empty: true
-        });
-        self.target.push('\n');
-        self.append_indent();
-        self.target.push(']');
+        };
+        op(&mut array);
+        array.finish();
     }
 
     pub fn string(&mut self, value: impl fmt::Display) {
         self.target.push('"');
-        write!(self.target, "{}", json_str(value));
+        let html = self.target.html;
+        write!(self.target, "{}", JsonStr { value, html });
         self.target.push('"');
     }
 
+    /// Writes the value as is.
+    ///
+    /// The caller has to make sure the value is valid JSON.
     pub fn raw(&mut self, value: impl fmt::Display) {
-        write!(self.target, "{}", json_str(value));
+        write!(self.target, "{}", value);
     }
 
-    fn append_indent(&mut self) {
-        for _ in 0..self.indent {
-            self.target.push_str("   ");
+    /// Writes a number.
+    ///
+    /// # Panics
+    ///
+    /// Floating point values that are not finite can’t be represented in
+    /// JSON. The method panics if it is given one. Use
+    /// [`try_number`][Self::try_number] if that can happen.
+    pub fn number(&mut self, value: impl JsonNumber) {
+        if self.try_number(value).is_err() {
+            panic!("non-finite number written to JSON")
+        }
+    }
+
+    /// Writes a number unless it is not finite.
+    ///
+    /// Nothing is written if an error is returned.
+    pub fn try_number(
+        &mut self, value: impl JsonNumber
+    ) -> Result<(), NonFiniteNumber> {
+        NonFiniteNumber::check(&value)?;
+        match (self.target.format, value.as_float()) {
+            (JsonFormat::Canonical, Some(value)) => {
+                write_canonical_float(self.target, value)
+            }
+            _ => write!(self.target, "{}", value)
+        }
+        Ok(())
+    }
+
+    /// Writes a boolean.
+    pub fn bool(&mut self, value: bool) {
+        self.target.push_str(if value { "true" } else { "false" })
+    }
+
+    /// Writes `null`.
+    pub fn null(&mut self) {
+        self.target.push_str("null")
+    }
+
+    /// Writes an optional value.
+    ///
+    /// If `value` is `None`, writes `null`. Otherwise `op` is called to
+    /// write the value.
+    pub fn optional<T>(
+        &mut self, value: Option<T>, op: impl FnOnce(&mut JsonValue, T)
+    ) {
+        match value {
+            Some(value) => op(self, value),
+            None => self.null()
+        }
+    }
+
+    /// Writes a value via its `Serialize` implementation.
+    ///
+    /// The value is written in the format of the builder and indented to
+    /// fit in with the surrounding output. If serialization fails, some
+    /// of the value may have been written already, so the output should
+    /// be discarded.
+    pub fn serialize(
+        &mut self, value: &impl Serialize
+    ) -> Result<(), serde_json::Error> {
+        if let JsonFormat::Canonical = self.target.format {
+            // Going through `Value` lets us sort the keys and write
+            // numbers ourselves.
+            self.json_value(&serde_json::to_value(value)?);
+            return Ok(())
+        }
+        let formatter = TargetFormatter {
+            format: self.target.format,
+            html: self.target.html,
+            level: self.indent,
+            has_value: false,
+            after_lt: false,
+        };
+        let mut ser = serde_json::Serializer::with_formatter(
+            TargetWriter(self.target), formatter
+        );
+        value.serialize(&mut ser)
+    }
+
+    fn json_value(&mut self, value: &serde_json::Value) {
+        use serde_json::Value;
+
+        match value {
+            Value::Null => self.null(),
+            Value::Bool(value) => self.bool(*value),
+            Value::Number(value) => {
+                if let Some(value) = value.as_u64() {
+                    self.number(value)
+                }
+                else if let Some(value) = value.as_i64() {
+                    self.number(value)
+                }
+                else if let Some(float) = value.as_f64() {
+                    self.number(float)
+                }
+                else {
+                    self.raw(value)
+                }
+            }
+            Value::String(value) => self.string(value),
+            Value::Array(value) => self.array(|json| {
+                for item in value {
+                    json.value(|json| json.json_value(item))
+                }
+            }),
+            Value::Object(value) => self.object(|json| {
+                for (key, item) in value {
+                    json.value(key, |json| json.json_value(item))
+                }
+            }),
         }
     }
 }
 
 
-//------------ json_str -----------------------------------------------------
+//------------ JsonNumber ---------------------------------------------------
 
-pub fn json_str(val: impl fmt::Display) -> impl fmt::Display {
-    struct WriteJsonStr<'a, 'f>(&'a mut fmt::Formatter<'f>);
-
-    impl<'a, 'f> fmt::Write for WriteJsonStr<'a, 'f> {
-        fn write_str(&mut self, mut s: &str) -> fmt::Result {
-            while let Some(idx) = s.find(|ch| ch == '"' || ch == '\\') {
-                self.0.write_str(&s[..idx])?;
-                self.0.write_str("\\")?;
-                write!(self.0, "{}", char::from(s.as_bytes()[idx]))?;
-                s = &s[idx + 1..];
+/// A type that can be written as a JSON number.
+///
+/// The `Display` implementation of the type must produce a valid JSON
+/// number for all values that are finite.
+pub trait JsonNumber: fmt::Display {
+    /// Returns whether the value can be represented in JSON.
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    /// Returns the value if it is a floating point number.
+    ///
+    /// This is used by the canonical format which has its own rules for
+    /// writing floating point numbers.
+    fn as_float(&self) -> Option<f64> {
+        None
+    }
+}
+
+macro_rules! json_number_int {
+    ( $( $ty:ty ),* ) => {
+        $( impl JsonNumber for $ty { } )*
+    }
+}
+
+json_number_int!(
+    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize
+);
+
+impl JsonNumber for f32 {
+    fn is_finite(&self) -> bool {
+        f32::is_finite(*self)
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        Some(f64::from(*self))
+    }
+}
+
+impl JsonNumber for f64 {
+    fn is_finite(&self) -> bool {
+        f64::is_finite(*self)
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        Some(*self)
+    }
+}
+
+impl<T: JsonNumber + ?Sized> JsonNumber for &T {
+    fn is_finite(&self) -> bool {
+        T::is_finite(self)
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        T::as_float(self)
+    }
+}
+
+/// Writes a floating point number as required by RFC 8785.
+///
+/// This is the algorithm used by ECMAScript’s `Number.prototype.toString`.
+/// The value must be finite.
+fn write_canonical_float(target: &mut JsonTarget, value: f64) {
+    if value == 0.0 {
+        return target.push('0')
+    }
+    if value < 0.0 {
+        target.push('-');
+    }
+
+    // The LowerExp format gives us the shortest digits that round-trip.
+    let exp_repr = format!("{:e}", value.abs());
+    let (mantissa, exp) = exp_repr.split_once('e').expect("no exponent");
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    let n = exp.parse::<i32>().expect("invalid exponent") + 1;
+
+    if k <= n && n <= 21 {
+        target.push_str(&digits);
+        for _ in 0..n - k {
+            target.push('0');
+        }
+    }
+    else if 0 < n && n <= 21 {
+        let (int, frac) = digits.split_at(n as usize);
+        target.push_str(int);
+        target.push('.');
+        target.push_str(frac);
+    }
+    else if -6 < n && n <= 0 {
+        target.push_str("0.");
+        for _ in 0..-n {
+            target.push('0');
+        }
+        target.push_str(&digits);
+    }
+    else {
+        let (first, rest) = digits.split_at(1);
+        target.push_str(first);
+        if !rest.is_empty() {
+            target.push('.');
+            target.push_str(rest);
+        }
+        write!(
+            target, "e{}{}", if n > 0 { '+' } else { '-' }, (n - 1).abs()
+        );
+    }
+}
+
+
+//------------ NonFiniteNumber ----------------------------------------------
+
+/// A floating point number that can’t be represented in JSON.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NonFiniteNumber;
+
+impl NonFiniteNumber {
+    fn check(value: &impl JsonNumber) -> Result<(), Self> {
+        if value.is_finite() {
+            Ok(())
+        }
+        else {
+            Err(NonFiniteNumber)
+        }
+    }
+}
+
+impl fmt::Display for NonFiniteNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("non-finite number can’t be written as JSON")
+    }
+}
+
+impl error::Error for NonFiniteNumber { }
+
+
+//------------ JsonFormat ---------------------------------------------------
+
+/// The format of the JSON output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JsonFormat {
+    /// No whitespace at all.
+    Compact,
+
+    /// Each member and element on its own line.
+    ///
+    /// The value is the number of spaces to indent each level with.
+    Pretty(usize),
+
+    /// The canonical form of RFC 8785.
+    ///
+    /// The output is compact and the members of objects are sorted by
+    /// their keys. Floating point numbers are written as defined by the
+    /// RFC. Integers are written as they are, so they need to be within
+    /// ±2^53 for the output to be fully canonical.
+    ///
+    /// Since sorting requires the members of an object to be collected
+    /// first, objects are kept in memory even when streaming.
+    Canonical,
+}
+
+impl JsonFormat {
+    fn is_pretty(self) -> bool {
+        matches!(self, JsonFormat::Pretty(_))
+    }
+}
+
+impl Default for JsonFormat {
+    fn default() -> Self {
+        JsonFormat::Pretty(3)
+    }
+}
+
+
+//------------ JsonTarget ---------------------------------------------------
+
+/// The place the JSON output goes to.
+///
+/// Output is collected in a buffer. For streaming builders, the buffer is
+/// sent to the channel whenever it has grown large enough.
+struct JsonTarget {
+    buf: String,
+    sink: JsonSink,
+    format: JsonFormat,
+
+    /// Whether strings are escaped for embedding in HTML.
+    html: bool,
+}
+
+enum JsonSink {
+    /// All output is kept in the buffer.
+    String,
+
+    /// Output is sent to the channel.
+    Channel(mpsc::Sender<Bytes>),
+
+    /// The receiving end has gone away, output is discarded.
+    Closed,
+}
+
+impl JsonTarget {
+    /// The buffer size at which a streaming target sends a chunk.
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn string(format: JsonFormat) -> Self {
+        JsonTarget {
+            buf: String::new(),
+            sink: JsonSink::String,
+            format,
+            html: false,
+        }
+    }
+
+    /// Creates a string target with the same settings as this one.
+    fn nested(&self) -> Self {
+        JsonTarget { html: self.html, ..Self::string(self.format) }
+    }
+
+    fn channel(tx: mpsc::Sender<Bytes>) -> Self {
+        JsonTarget {
+            buf: String::with_capacity(Self::CHUNK_SIZE),
+            sink: JsonSink::Channel(tx),
+            format: JsonFormat::default(),
+            html: false,
+        }
+    }
+
+    fn push(&mut self, ch: char) {
+        self.buf.push(ch)
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.buf.push_str(s)
+    }
+
+    /// Starts a new line at the given level if the format is pretty.
+    fn newline(&mut self, level: usize) {
+        if let JsonFormat::Pretty(indent) = self.format {
+            self.buf.push('\n');
+            for _ in 0..level * indent {
+                self.buf.push(' ');
+            }
+        }
+    }
+
+    /// Returns whether the receiving end of the channel has gone away.
+    fn is_closed(&self) -> bool {
+        match self.sink {
+            JsonSink::String => false,
+            JsonSink::Channel(ref tx) => tx.is_closed(),
+            JsonSink::Closed => true,
+        }
+    }
+
+    /// Sends the buffer if it is large enough.
+    fn maybe_flush(&mut self) {
+        if self.buf.len() >= Self::CHUNK_SIZE {
+            self.flush()
+        }
+    }
+
+    /// Sends whatever is in the buffer.
+    ///
+    /// This blocks if the channel is full. If the receiver doesn’t take
+    /// the chunk in time, the target is closed.
+    fn flush(&mut self) {
+        match self.sink {
+            JsonSink::String => { }
+            JsonSink::Channel(ref tx) => {
+                if self.buf.is_empty() {
+                    return
+                }
+                let chunk = mem::replace(
+                    &mut self.buf, String::with_capacity(Self::CHUNK_SIZE)
+                );
+                let sent = tokio::runtime::Handle::current().block_on(
+                    tokio::time::timeout(
+                        JsonBuilder::STREAM_TIMEOUT, tx.send(chunk.into())
+                    )
+                );
+                if !matches!(sent, Ok(Ok(()))) {
+                    self.sink = JsonSink::Closed
+                }
+            }
+            JsonSink::Closed => self.buf.clear(),
+        }
+    }
+}
+
+impl WriteOrPanic for JsonTarget {
+    fn write_fmt(&mut self, args: fmt::Arguments) {
+        WriteOrPanic::write_fmt(&mut self.buf, args)
+    }
+}
+
+
+//------------ TargetWriter and TargetFormatter -----------------------------
+
+/// Allows serde_json to write to a target.
+struct TargetWriter<'a>(&'a mut JsonTarget);
+
+impl io::Write for TargetWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        // serde_json only ever writes complete characters.
+        let buf = str::from_utf8(buf).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, err)
+        })?;
+        self.0.push_str(buf);
+        self.0.maybe_flush();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Makes serde_json produce output in the compact or pretty format.
+///
+/// This is serde_json’s own `PrettyFormatter` except that it starts at
+/// an arbitrary level, can leave out whitespace altogether, and can
+/// escape strings for HTML.
+struct TargetFormatter {
+    format: JsonFormat,
+    html: bool,
+    level: usize,
+    has_value: bool,
+
+    /// Whether the last character of a string fragment was a `<`.
+    after_lt: bool,
+}
+
+impl TargetFormatter {
+    fn newline<W: ?Sized + io::Write>(
+        &self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        if let JsonFormat::Pretty(indent) = self.format {
+            writer.write_all(b"\n")?;
+            for _ in 0..self.level * indent {
+                writer.write_all(b" ")?;
             }
-            self.0.write_str(s)
+        }
+        Ok(())
+    }
+
+    fn begin<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, open: &[u8]
+    ) -> Result<(), io::Error> {
+        self.level += 1;
+        self.has_value = false;
+        writer.write_all(open)
+    }
+
+    fn end<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, close: &[u8]
+    ) -> Result<(), io::Error> {
+        self.level -= 1;
+        if self.has_value {
+            self.newline(writer)?;
+        }
+        writer.write_all(close)
+    }
+
+    fn begin_item<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, first: bool
+    ) -> Result<(), io::Error> {
+        if !first {
+            writer.write_all(b",")?;
+        }
+        self.newline(writer)
+    }
+}
+
+impl serde_json::ser::Formatter for TargetFormatter {
+    fn begin_array<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.begin(writer, b"[")
+    }
+
+    fn end_array<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.end(writer, b"]")
+    }
+
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, first: bool
+    ) -> Result<(), io::Error> {
+        self.begin_item(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + io::Write>(
+        &mut self, _writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn begin_object<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.begin(writer, b"{")
+    }
+
+    fn end_object<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.end(writer, b"}")
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, first: bool
+    ) -> Result<(), io::Error> {
+        self.begin_item(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        if self.format.is_pretty() {
+            writer.write_all(b": ")
+        }
+        else {
+            writer.write_all(b":")
         }
     }
 
-    struct JsonStr<T>(T);
+    fn end_object_value<W: ?Sized + io::Write>(
+        &mut self, _writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.has_value = true;
+        Ok(())
+    }
 
-    impl<T: fmt::Display> fmt::Display for JsonStr<T> {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            use std::fmt::Write;
+    fn begin_string<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W
+    ) -> Result<(), io::Error> {
+        self.after_lt = false;
+        writer.write_all(b"\"")
+    }
 
-            write!(&mut WriteJsonStr(f), "{}", self.0)
+    /// Writes a part of a string that needs no JSON escaping.
+    ///
+    /// If the output is HTML-safe, adds the escapes of [`json_str_html`].
+    /// Characters escaped by serde_json in between fragments don’t reset
+    /// `after_lt`, which at worst escapes a slash needlessly.
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self, writer: &mut W, fragment: &str
+    ) -> Result<(), io::Error> {
+        if !self.html {
+            return writer.write_all(fragment.as_bytes())
+        }
+        let mut start = 0;
+        for (idx, ch) in fragment.char_indices() {
+            let after_lt = mem::replace(&mut self.after_lt, ch == '<');
+            let escape = match ch {
+                '/' if after_lt => "\\/",
+                '\u{2028}' => "\\u2028",
+                '\u{2029}' => "\\u2029",
+                _ => continue
+            };
+            writer.write_all(&fragment.as_bytes()[start..idx])?;
+            writer.write_all(escape.as_bytes())?;
+            start = idx + ch.len_utf8();
         }
+        writer.write_all(&fragment.as_bytes()[start..])
     }
+}
+
+
+//------------ json_str -----------------------------------------------------
+
+/// Escapes a value for use inside a JSON string.
+///
+/// The value is escaped as it is formatted, so no intermediary string is
+/// allocated. Quotation marks, backslashes, and control characters are
+/// escaped as required by RFC 8259. All other characters, including
+/// those outside the Basic Multilingual Plane, are passed through as is.
+pub fn json_str(val: impl fmt::Display) -> impl fmt::Display {
+    JsonStr { value: val, html: false }
+}
+
+/// Escapes a value for use inside a JSON string embedded in HTML.
+///
+/// In addition to the escaping done by [`json_str`], this also escapes
+/// the slash in `</` so that the string can’t close a script element
+/// and the line and paragraph separators U+2028 and U+2029 which are not
+/// allowed in string literals of older JavaScript versions.
+pub fn json_str_html(val: impl fmt::Display) -> impl fmt::Display {
+    JsonStr { value: val, html: true }
+}
 
-    JsonStr(val)
+struct JsonStr<T> {
+    value: T,
+    html: bool,
+}
+
+impl<T: fmt::Display> fmt::Display for JsonStr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use std::fmt::Write;
+
+        write!(
+            &mut WriteJsonStr { f, html: self.html, after_lt: false },
+            "{}", self.value
+        )
+    }
+}
+
+struct WriteJsonStr<'a, 'f> {
+    f: &'a mut fmt::Formatter<'f>,
+    html: bool,
+
+    /// Whether the last character written was a `<`.
+    ///
+    /// This needs to be kept across calls to `write_str` since the value
+    /// may be written in arbitrary pieces.
+    after_lt: bool,
+}
+
+impl fmt::Write for WriteJsonStr<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+        for (idx, ch) in s.char_indices() {
+            let after_lt = mem::replace(&mut self.after_lt, ch == '<');
+            let escape = match ch {
+                '"' => Some("\\\""),
+                '\\' => Some("\\\\"),
+                '\n' => Some("\\n"),
+                '\r' => Some("\\r"),
+                '\t' => Some("\\t"),
+                '\u{08}' => Some("\\b"),
+                '\u{0c}' => Some("\\f"),
+                '\u{00}'..='\u{1f}' => None,
+                '/' if self.html && after_lt => Some("\\/"),
+                '\u{2028}' if self.html => Some("\\u2028"),
+                '\u{2029}' if self.html => Some("\\u2029"),
+                _ => continue
+            };
+            self.f.write_str(&s[start..idx])?;
+            match escape {
+                Some(escape) => self.f.write_str(escape)?,
+                None => write!(self.f, "\\u{:04x}", u32::from(ch))?,
+            }
+            start = idx + ch.len_utf8();
+        }
+        self.f.write_str(&s[start..])
+    }
 }
 
 
@@ -424,6 +1358,303 @@ mod test {
             format!("{}", json_str("foo\\")).as_str(),
             "foo\\\\"
         );
+        assert_eq!(
+            format!("{}", json_str("a\nb\tc\r\u{8}\u{c}")),
+            "a\\nb\\tc\\r\\b\\f"
+        );
+        assert_eq!(
+            format!("{}", json_str("\0\u{1}\u{1f}\u{7f}")),
+            "\\u0000\\u0001\\u001f\u{7f}"
+        );
+        assert_eq!(
+            format!("{}", json_str("</script>\u{2028}😀")),
+            "</script>\u{2028}😀"
+        );
+    }
+
+    #[test]
+    fn test_json_str_html() {
+        assert_eq!(
+            format!("{}", json_str_html("</script>\u{2028}\u{2029}/")),
+            "<\\/script>\\u2028\\u2029/"
+        );
+        // The slash may arrive in a separate write.
+        assert_eq!(
+            format!("{}", json_str_html(format_args!("{}{}", "<", "/a"))),
+            "<\\/a"
+        );
+        assert_eq!(
+            format!("{}", json_str_html("a\"<\"/😀")), "a\\\"<\\\"/😀"
+        );
+    }
+
+    #[test]
+    fn html_safe() {
+        #[derive(serde::Serialize)]
+        struct Value {
+            text: &'static str,
+        }
+
+        let value = Value { text: "</script>\u{2028}" };
+        for format in [JsonFormat::Compact, JsonFormat::Canonical] {
+            assert_eq!(
+                JsonBuilder::build(|json| {
+                    json.set_format(format);
+                    json.set_html_safe(true);
+                    json.object(|json| {
+                        json.string("</a>", "</b>");
+                        json.serialize("c", &value).unwrap();
+                    })
+                }),
+                "{\"<\\/a>\":\"<\\/b>\",\
+                 \"c\":{\"text\":\"<\\/script>\\u2028\"}}"
+            );
+        }
+        assert_eq!(
+            JsonBuilder::build(|json| {
+                json.set_format(JsonFormat::Compact);
+                json.string("</a>")
+            }),
+            "\"</a>\""
+        );
+    }
+
+    #[test]
+    fn scalars() {
+        let parse = |json: String| {
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()
+        };
+        assert_eq!(
+            parse(JsonBuilder::build(|json| json.array(|json| {
+                json.number(-12i8);
+                json.number(u64::MAX);
+                json.number(1.5f64);
+                assert_eq!(json.try_number(f64::NAN), Err(NonFiniteNumber));
+                json.try_number(0.5f32).unwrap();
+                json.bool(true);
+                json.null();
+                json.optional(Some(2), |json, value| json.number(value));
+                json.optional(None::<u8>, |json, value| json.number(value));
+                json.raw("{}");
+            }))),
+            serde_json::json!([
+                -12, u64::MAX, 1.5, 0.5, true, null, 2, null, {}
+            ])
+        );
+        assert_eq!(
+            parse(JsonBuilder::build(|json| json.object(|json| {
+                json.number("n", 0.25f32);
+                assert_eq!(
+                    json.try_number("i", f32::NEG_INFINITY),
+                    Err(NonFiniteNumber)
+                );
+                json.bool("b", false);
+                json.null("z");
+                json.optional("s", Some("x"), |json, v| json.string(v));
+            }))),
+            serde_json::json!({ "n": 0.25, "b": false, "z": null, "s": "x" })
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn non_finite_number() {
+        JsonBuilder::build(|json| json.number(f64::INFINITY));
+    }
+
+    fn build_format(format: JsonFormat) -> String {
+        JsonBuilder::build(|json| {
+            json.set_format(format);
+            json.object(|json| {
+                json.string("b", "x");
+                json.array("a", |json| {
+                    json.number(1);
+                    json.object(|_| { });
+                    json.array(|_| { });
+                });
+                json.object("c", |json| json.bool("d", true));
+            })
+        })
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            build_format(JsonFormat::default()),
+            "{\n   \"b\": \"x\",\n   \"a\": [\n      1,\n      {},\n      \
+             []\n   ],\n   \"c\": {\n      \"d\": true\n   }\n}"
+        );
+        assert_eq!(
+            build_format(JsonFormat::Pretty(1)),
+            "{\n \"b\": \"x\",\n \"a\": [\n  1,\n  {},\n  []\n ],\n \
+             \"c\": {\n  \"d\": true\n }\n}"
+        );
+        assert_eq!(
+            build_format(JsonFormat::Compact),
+            "{\"b\":\"x\",\"a\":[1,{},[]],\"c\":{\"d\":true}}"
+        );
+        assert_eq!(
+            build_format(JsonFormat::Canonical),
+            "{\"a\":[1,{},[]],\"b\":\"x\",\"c\":{\"d\":true}}"
+        );
+    }
+
+    #[test]
+    #[allow(clippy::excessive_precision)]
+    fn canonical() {
+        let build = |op: fn(&mut JsonArray)| {
+            JsonBuilder::build(|json| {
+                json.set_format(JsonFormat::Canonical);
+                json.array(op)
+            })
+        };
+
+        // Examples from RFC 8785, appendix B and section 3.2.3.
+        assert_eq!(
+            build(|json| {
+                json.number(1e30);
+                json.number(4.50);
+                json.number(2e-3);
+                json.number(0.000000000000000000000000001);
+                json.number(9007199254740992.0);
+                json.number(295147905179352830000.0);
+                json.number(-0.0);
+                json.number(333333333.33333329);
+                json.number(1e21);
+                json.number(1e-7);
+                json.number(0.000001);
+                json.number(-1.5e300);
+                json.number(12u8);
+            }),
+            "[1e+30,4.5,0.002,1e-27,9007199254740992,\
+             295147905179352830000,0,333333333.3333333,1e+21,1e-7,\
+             0.000001,-1.5e+300,12]"
+        );
+        assert_eq!(
+            build(|json| json.object(|json| {
+                for key in [
+                    "\u{20ac}", "\r", "\u{fb33}", "1", "\u{1f600}",
+                    "\u{80}", "\u{f6}",
+                ] {
+                    json.null(key);
+                }
+            })),
+            "[{\"\\r\":null,\"1\":null,\"\u{80}\":null,\"\u{f6}\":null,\
+             \"\u{20ac}\":null,\"\u{1f600}\":null,\"\u{fb33}\":null}]"
+        );
+    }
+
+    #[derive(serde::Serialize)]
+    struct Item {
+        name: &'static str,
+        z: Option<f64>,
+        tags: Vec<u32>,
+        empty: Vec<u32>,
+    }
+
+    #[test]
+    fn serialize() {
+        let item = Item {
+            name: "a\nb", z: Some(1.5), tags: vec![1, 2], empty: vec![],
+        };
+        for format in [
+            JsonFormat::default(), JsonFormat::Pretty(2),
+            JsonFormat::Compact, JsonFormat::Canonical,
+        ] {
+            let serialized = JsonBuilder::build(|json| {
+                json.set_format(format);
+                json.object(|json| {
+                    json.serialize("item", &item).unwrap();
+                    json.array("items", |json| {
+                        json.serialize(&item).unwrap();
+                    });
+                })
+            });
+            let built = JsonBuilder::build(|json| {
+                json.set_format(format);
+                let item = |json: &mut JsonValue| json.object(|json| {
+                    json.string("name", item.name);
+                    json.optional("z", item.z, |json, z| json.number(z));
+                    json.array("tags", |json| {
+                        item.tags.iter().for_each(|tag| json.number(tag))
+                    });
+                    json.array("empty", |_| { });
+                });
+                json.object(|json| {
+                    json.value("item", item);
+                    json.array("items", |json| json.value(item));
+                })
+            });
+            assert_eq!(serialized, built);
+        }
+    }
+
+    #[tokio::test]
+    async fn ok_serialize() {
+        let resp = JsonBuilder::ok_serialize(&[1, 2]).into_hyper();
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+            "[\n   1,\n   2\n]"
+        );
+
+        let mut map = std::collections::HashMap::new();
+        map.insert((1, 2), 3);
+        assert_eq!(
+            JsonBuilder::ok_serialize(&map).into_hyper().status(),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    fn build_large(json: &mut JsonBuilder) {
+        json.object(|json| {
+            json.array("items", |json| {
+                for i in 0..20_000 {
+                    json.object(|json| {
+                        json.raw("id", i);
+                        json.string("name", format_args!("item {}", i));
+                    })
+                }
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let body = hyper::body::to_bytes(
+            JsonBuilder::stream(build_large)
+        ).await.unwrap();
+        assert!(body.len() > JsonTarget::CHUNK_SIZE);
+        assert_eq!(body, JsonBuilder::build(build_large).as_bytes());
+    }
+
+    #[tokio::test]
+    async fn stream_closed() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let body = JsonBuilder::stream(move |json| {
+            started_tx.send(()).unwrap();
+            json.array(|json| {
+                while !json.is_closed() {
+                    json.number(1);
+                }
+            });
+            tx.send(json.is_closed()).unwrap();
+        });
+        started_rx.await.unwrap();
+        drop(body);
+        assert!(rx.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stream_panic() {
+        let body = JsonBuilder::stream(|json| {
+            json.array(|json| {
+                json.raw(1);
+                panic!("broken");
+            })
+        });
+        assert!(hyper::body::to_bytes(body).await.is_err());
     }
 }
 
@@ -1,13 +1,27 @@
 //! Tools for building web services with Hyper
 
+// Handlers return responses as errors, so these are large by design.
+#![allow(clippy::result_large_err)]
+
 pub use hyper;
 
 pub use self::request::{Request, RequestPath};
 pub use self::response::{Response, ResponseBuilder};
 
+pub mod compress;
+pub mod conditional;
 pub mod date;
+pub mod embed;
+pub mod etag;
+pub mod files;
 pub mod json;
+pub mod multipart;
+mod negotiate;
+pub mod query;
+pub mod range;
 pub mod request;
 pub mod response;
+pub mod router;
 pub mod server;
+pub mod tls;
 
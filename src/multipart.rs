@@ -0,0 +1,545 @@
+//! Parsing `multipart/form-data` request bodies.
+//!
+//! Use [`Request::multipart`][crate::Request::multipart] to parse a
+//! request body. Text fields are collected into a [`RequestQuery`] while
+//! file parts are streamed to a [`MultipartSink`] provided by the caller.
+
+use hyper::Body;
+use hyper::body::HttpBody;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use crate::request::{RequestQuery, split_unquoted};
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ MultipartLimits -----------------------------------------------
+
+/// The limits to apply when parsing a multipart body.
+#[derive(Clone, Copy, Debug)]
+pub struct MultipartLimits {
+    /// The maximum number of parts.
+    parts: usize,
+
+    /// The maximum size of a file part in bytes.
+    file_size: u64,
+
+    /// The maximum size of a text field in bytes.
+    field_size: usize,
+}
+
+impl MultipartLimits {
+    /// The maximum size of the headers of a single part.
+    const HEADER_SIZE: usize = 8 * 1024;
+
+    /// Creates the default limits.
+    ///
+    /// These allow 100 parts, files of up to 10 MiB, and text fields of
+    /// up to 64 KiB.
+    pub fn new() -> Self {
+        MultipartLimits {
+            parts: 100,
+            file_size: 10 * 1024 * 1024,
+            field_size: 64 * 1024,
+        }
+    }
+
+    /// Sets the maximum number of parts.
+    pub fn parts(self, parts: usize) -> Self {
+        MultipartLimits { parts, ..self }
+    }
+
+    /// Sets the maximum size of a file part in bytes.
+    pub fn file_size(self, file_size: u64) -> Self {
+        MultipartLimits { file_size, ..self }
+    }
+
+    /// Sets the maximum size of a text field in bytes.
+    pub fn field_size(self, field_size: usize) -> Self {
+        MultipartLimits { field_size, ..self }
+    }
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//------------ FilePart ------------------------------------------------------
+
+/// Information about a file part of a multipart body.
+#[derive(Clone, Debug)]
+pub struct FilePart {
+    name: String,
+    filename: String,
+    content_type: Option<String>,
+}
+
+impl FilePart {
+    /// Returns the name of the form field.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file name as given by the client.
+    ///
+    /// This is whatever the client sent and must not be used as a path
+    /// without sanitizing it first.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns the content type of the part if given.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+
+//------------ MultipartSink -------------------------------------------------
+
+/// Receives the file parts of a multipart body.
+///
+/// The trait is implemented for closures taking a [`FilePart`] and
+/// returning a writer.
+pub trait MultipartSink {
+    /// The type to write the content of a file part to.
+    type Writer: AsyncWrite + Unpin;
+
+    /// Starts a new file part.
+    ///
+    /// Returns the writer the content of the part is written to. The
+    /// writer is shut down once the part is complete. If an error is
+    /// returned, parsing stops and the error is returned as is.
+    fn file(&mut self, part: &FilePart) -> Result<Self::Writer, Response>;
+}
+
+impl<F, W> MultipartSink for F
+where
+    F: FnMut(&FilePart) -> Result<W, Response>,
+    W: AsyncWrite + Unpin,
+{
+    type Writer = W;
+
+    fn file(&mut self, part: &FilePart) -> Result<W, Response> {
+        self(part)
+    }
+}
+
+
+//------------ parse ---------------------------------------------------------
+
+/// Parses a multipart body with the given boundary.
+///
+/// If a part fails, parsing stops. Files already handed to the sink
+/// will have been written partially or completely.
+pub(crate) async fn parse<S: MultipartSink>(
+    body: Body, boundary: &str, limits: MultipartLimits, mut sink: S,
+) -> Result<RequestQuery, Response> {
+    let mut reader = Reader::new(body, boundary);
+    let mut res = RequestQuery::default();
+    let mut parts = 0;
+    while let Some(part) = reader.next_part().await? {
+        parts += 1;
+        if parts > limits.parts {
+            return Err(too_large("too many parts"))
+        }
+        match part.filename {
+            Some(filename) => {
+                let mut writer = sink.file(&FilePart {
+                    name: part.name,
+                    filename,
+                    content_type: part.content_type,
+                })?;
+                let mut size = 0u64;
+                while let Some(data) = reader.data().await? {
+                    size += data.len() as u64;
+                    if size > limits.file_size {
+                        return Err(too_large("file too large"))
+                    }
+                    writer.write_all(&data).await.map_err(|_| {
+                        Response::internal_server_error()
+                    })?;
+                }
+                writer.shutdown().await.map_err(|_| {
+                    Response::internal_server_error()
+                })?;
+            }
+            None => {
+                let mut value = Vec::new();
+                while let Some(data) = reader.data().await? {
+                    if value.len() + data.len() > limits.field_size {
+                        return Err(too_large("field too large"))
+                    }
+                    value.extend_from_slice(&data);
+                }
+                let value = String::from_utf8(value).map_err(|_| {
+                    invalid("field is not valid UTF-8")
+                })?;
+                res.insert(part.name, value);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Returns whether a boundary parameter is acceptable.
+pub(crate) fn is_valid_boundary(boundary: &str) -> bool {
+    !boundary.is_empty() && boundary.len() <= 70
+        && !boundary.ends_with(' ')
+        && boundary.bytes().all(|ch| {
+            ch.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&ch)
+        })
+}
+
+/// Creates a Bad Request response for an invalid body.
+fn invalid(msg: &str) -> Response {
+    ResponseBuilder::new().bad_request()
+        .content_type(ContentType::TEXT)
+        .body(format!("Invalid multipart body: {}.", msg))
+}
+
+/// Creates a Payload Too Large response for an exceeded limit.
+fn too_large(msg: &str) -> Response {
+    ResponseBuilder::new().payload_too_large()
+        .content_type(ContentType::TEXT)
+        .body(format!("Multipart body too large: {}.", msg))
+}
+
+
+//------------ Reader --------------------------------------------------------
+
+/// Splits a body into parts.
+struct Reader {
+    /// The remaining body.
+    body: Body,
+
+    /// Data read from the body but not yet processed.
+    buf: Vec<u8>,
+
+    /// The delimiter, i.e., CRLF, two dashes, and the boundary.
+    delimiter: Vec<u8>,
+
+    /// Whether we have seen the close delimiter.
+    done: bool,
+}
+
+/// The headers of a part we are interested in.
+struct PartHeaders {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl Reader {
+    fn new(body: Body, boundary: &str) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Reader {
+            body,
+            // The first delimiter may appear at the very start of the body
+            // without a preceding line break, so we pretend there is one.
+            buf: b"\r\n".to_vec(),
+            delimiter,
+            done: false,
+        }
+    }
+
+    /// Appends the next chunk of the body to the buffer.
+    async fn fill(&mut self) -> Result<(), Response> {
+        match self.body.data().await {
+            Some(Ok(chunk)) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(())
+            }
+            Some(Err(_)) => Err(Response::bad_request()),
+            None => Err(invalid("unexpected end of body")),
+        }
+    }
+
+    /// Proceeds to the next part and returns its headers.
+    ///
+    /// Any unread data of the current part is skipped.
+    async fn next_part(&mut self) -> Result<Option<PartHeaders>, Response> {
+        if self.done {
+            return Ok(None)
+        }
+
+        // Skip to and over the next delimiter.
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.buf.drain(..pos + self.delimiter.len());
+                break
+            }
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                self.buf.drain(..self.buf.len() - keep);
+            }
+            self.fill().await?;
+        }
+
+        // Two dashes mark the close delimiter. Everything after it is
+        // epilogue which we ignore.
+        while self.buf.len() < 2 {
+            self.fill().await?;
+        }
+        if self.buf.starts_with(b"--") {
+            self.done = true;
+            return Ok(None)
+        }
+
+        // Skip optional whitespace and the line break.
+        let end = self.find_within(b"\r\n", 256).await?;
+        if !self.buf[..end].iter().all(|ch| *ch == b' ' || *ch == b'\t') {
+            return Err(invalid("garbage after boundary"))
+        }
+        self.buf.drain(..end + 2);
+
+        // The headers end with an empty line. If there are no headers at
+        // all, the empty line comes right away.
+        while self.buf.len() < 2 {
+            self.fill().await?;
+        }
+        if self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+            return Err(invalid("missing Content-Disposition"))
+        }
+        let end = self.find_within(
+            b"\r\n\r\n", MultipartLimits::HEADER_SIZE
+        ).await?;
+        let headers = String::from_utf8(
+            self.buf.drain(..end + 4).take(end).collect()
+        ).map_err(|_| invalid("headers are not valid UTF-8"))?;
+        PartHeaders::parse(&headers).map(Some)
+    }
+
+    /// Finds `needle` within the first `limit` bytes of the buffer.
+    async fn find_within(
+        &mut self, needle: &[u8], limit: usize
+    ) -> Result<usize, Response> {
+        loop {
+            if let Some(pos) = find(&self.buf, needle) {
+                if pos <= limit {
+                    return Ok(pos)
+                }
+            }
+            if self.buf.len() > limit + needle.len() {
+                return Err(too_large("part headers too large"))
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the next chunk of data of the current part.
+    ///
+    /// Returns `Ok(None)` once the part is complete.
+    async fn data(&mut self) -> Result<Option<Vec<u8>>, Response> {
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                if pos == 0 {
+                    return Ok(None)
+                }
+                return Ok(Some(self.buf.drain(..pos).collect()))
+            }
+
+            // The end of the buffer may be the start of the delimiter, so
+            // we need to keep it around.
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let end = self.buf.len() - keep;
+                return Ok(Some(self.buf.drain(..end).collect()))
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+impl PartHeaders {
+    fn parse(headers: &str) -> Result<Self, Response> {
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in headers.split("\r\n") {
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                invalid("malformed part header")
+            })?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                disposition = Some(value)
+            }
+            else if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.to_string())
+            }
+        }
+        let disposition = disposition.ok_or_else(|| {
+            invalid("missing Content-Disposition")
+        })?;
+
+        let mut items = split_unquoted(disposition, ';');
+        if !items.next().unwrap_or("").trim().eq_ignore_ascii_case(
+            "form-data"
+        ) {
+            return Err(invalid("part is not form-data"))
+        }
+        let mut name = None;
+        let mut filename = None;
+        for item in items {
+            let (key, value) = match item.split_once('=') {
+                Some(some) => some,
+                None => continue,
+            };
+            let key = key.trim();
+            if key.eq_ignore_ascii_case("name") {
+                name = Some(unquote(value.trim()))
+            }
+            else if key.eq_ignore_ascii_case("filename") {
+                filename = Some(unquote(value.trim()))
+            }
+        }
+        Ok(PartHeaders {
+            name: name.ok_or_else(|| invalid("missing field name"))?,
+            filename,
+            content_type,
+        })
+    }
+}
+
+/// Removes quotes and escapes from a parameter value.
+fn unquote(value: &str) -> String {
+    let value = match value.strip_prefix('"').and_then(|value| {
+        value.strip_suffix('"')
+    }) {
+        Some(value) => value,
+        None => return value.into()
+    };
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            if let Some(ch) = chars.next() {
+                res.push(ch)
+            }
+        }
+        else {
+            res.push(ch)
+        }
+    }
+    res
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::StatusCode;
+    use tokio::io::AsyncReadExt;
+
+    const BODY: &str = "\
+        preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"asn\"\r\n\
+        \r\n\
+        64496\r\n\
+        --XyZ  \r\n\
+        Content-Disposition: form-data; name=\"asn\"\r\n\
+        \r\n\
+        64497\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; \
+            filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n--XyNot\r\nline two\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    /// Returns a body delivering `data` in chunks of `size` bytes.
+    fn chunked(data: &'static str, size: usize) -> Body {
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in data.as_bytes().chunks(size) {
+                if tx.send_data(chunk.into()).await.is_err() {
+                    break
+                }
+            }
+        });
+        body
+    }
+
+    async fn run(
+        body: Body, limits: MultipartLimits
+    ) -> Result<RequestQuery, Response> {
+        parse(
+            body, "XyZ", limits, |_: &FilePart| Ok(tokio::io::sink())
+        ).await
+    }
+
+    #[tokio::test]
+    async fn parse_fields_and_files() {
+        for size in [1, 3, 7, 1000] {
+            let (writer, mut reader) = tokio::io::duplex(1024);
+            let mut writer = Some(writer);
+            let query = parse(
+                chunked(BODY, size), "XyZ", MultipartLimits::new(),
+                |part: &FilePart| {
+                    assert_eq!(part.name(), "file");
+                    assert_eq!(part.filename(), "a \"b\".txt");
+                    assert_eq!(part.content_type(), Some("text/plain"));
+                    Ok(writer.take().unwrap())
+                }
+            ).await.unwrap();
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).await.unwrap();
+            assert_eq!(
+                query.get("asn").unwrap(),
+                ["64496".to_string(), "64497".to_string()]
+            );
+            assert_eq!(content, b"line one\r\n--XyNot\r\nline two");
+        }
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let status = |res: Result<_, Response>| {
+            res.unwrap_err().into_hyper().status()
+        };
+        assert_eq!(
+            status(run(
+                chunked(BODY, 5), MultipartLimits::new().parts(2)
+            ).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status(run(
+                chunked(BODY, 5), MultipartLimits::new().file_size(10)
+            ).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status(run(
+                chunked(BODY, 5), MultipartLimits::new().field_size(4)
+            ).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status(run(
+                chunked(&BODY[..100], 5), MultipartLimits::new()
+            ).await),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn boundary() {
+        assert!(is_valid_boundary("----WebKitFormBoundary7MA4YWxk"));
+        assert!(!is_valid_boundary(""));
+        assert!(!is_valid_boundary("foo "));
+        assert!(!is_valid_boundary("foo\"bar"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Content negotiation.
+//!
+//! This module implements the proactive negotiation of RFC 9110,
+//! section 12. Use [`Request::negotiate`][crate::Request::negotiate]
+//! to pick the content type of a response.
+
+use hyper::header::{ACCEPT, ACCEPT_ENCODING, HeaderMap};
+use crate::request::split_unquoted;
+use crate::response::ContentType;
+
+
+//------------ select_content_type -------------------------------------------
+
+/// Selects the best of the offered content types for the given headers.
+///
+/// Returns the index of the selected type or `None` if none of the offers
+/// is acceptable. Ties are resolved in favour of the earlier offer. If
+/// there is no Accept header, the first offer is selected.
+pub(crate) fn select_content_type(
+    headers: &HeaderMap, offers: &[ContentType]
+) -> Option<usize> {
+    let mut ranges = Vec::new();
+    for value in headers.get_all(ACCEPT) {
+        // An Accept header we can’t make sense of is treated as absent.
+        if let Ok(value) = value.to_str() {
+            ranges.extend(
+                split_unquoted(value, ',').filter_map(MediaRange::parse)
+            )
+        }
+    }
+    if ranges.is_empty() {
+        return if offers.is_empty() { None } else { Some(0) }
+    }
+
+    let mut best = None;
+    let mut best_quality = 0;
+    for (idx, offer) in offers.iter().enumerate() {
+        let offer = match MediaType::parse(offer.as_str()) {
+            Some(offer) => offer,
+            None => continue
+        };
+        let quality = ranges.iter().filter_map(|range| {
+            range.specificity(&offer).map(|spec| (spec, range.quality))
+        }).max_by_key(|(spec, _)| *spec).map(|(_, quality)| quality);
+        if let Some(quality) = quality {
+            if quality > best_quality {
+                best = Some(idx);
+                best_quality = quality;
+            }
+        }
+    }
+    best
+}
+
+
+//------------ select_encoding -----------------------------------------------
+
+/// Selects the best of the offered content codings for the given headers.
+///
+/// Returns the index of the selected coding or `None` if the content
+/// should not be encoded at all. Ties are resolved in favour of the
+/// earlier offer. `x-gzip` is treated as an alias for `gzip`.
+pub(crate) fn select_encoding(
+    headers: &HeaderMap, offers: &[&str]
+) -> Option<usize> {
+    let mut quality = vec![None; offers.len()];
+    let mut any = None;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue
+        };
+        for item in value.split(',') {
+            let mut item = item.split(';');
+            let coding = item.next().unwrap_or("").trim();
+            let q = match item.next() {
+                Some(param) => {
+                    let q = param.trim().strip_prefix("q=").or_else(|| {
+                        param.trim().strip_prefix("Q=")
+                    });
+                    match q.and_then(parse_quality) {
+                        Some(q) => q,
+                        None => continue
+                    }
+                }
+                None => 1000
+            };
+            if coding == "*" {
+                any = Some(q);
+                continue
+            }
+            let alias = coding.eq_ignore_ascii_case("x-gzip");
+            let coding = if alias { "gzip" } else { coding };
+            if let Some(idx) = offers.iter().position(|offer| {
+                coding.eq_ignore_ascii_case(offer)
+            }) {
+                // An explicit gzip wins over the x-gzip alias.
+                if !alias || quality[idx].is_none() {
+                    quality[idx] = Some(q);
+                }
+            }
+        }
+    }
+
+    let mut best = None;
+    let mut best_quality = 0;
+    for (idx, quality) in quality.into_iter().enumerate() {
+        let quality = quality.or(any).unwrap_or(0);
+        if quality > best_quality {
+            best = Some(idx);
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+
+//------------ MediaType -----------------------------------------------------
+
+/// A media type with its parameters.
+struct MediaType<'a> {
+    ty: &'a str,
+    subtype: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> MediaType<'a> {
+    fn parse(value: &'a str) -> Option<Self> {
+        let mut items = split_unquoted(value, ';');
+        let (ty, subtype) = items.next()?.trim().split_once('/')?;
+        if !is_token(ty) || !is_token(subtype) {
+            return None
+        }
+        let mut params = Vec::new();
+        for item in items {
+            let item = item.trim();
+            if item.is_empty() {
+                continue
+            }
+            let (name, value) = item.split_once('=')?;
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|value| {
+                value.strip_suffix('"')
+            }).unwrap_or(value);
+            params.push((name.trim(), value));
+        }
+        Some(MediaType { ty, subtype, params })
+    }
+}
+
+
+//------------ MediaRange ----------------------------------------------------
+
+/// A media range from an Accept header.
+struct MediaRange<'a> {
+    media: MediaType<'a>,
+
+    /// The quality in thousandths.
+    quality: u16,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(value: &'a str) -> Option<Self> {
+        let mut media = MediaType::parse(value)?;
+        if media.ty == "*" && media.subtype != "*" {
+            return None
+        }
+
+        // The q parameter separates media type parameters from extension
+        // parameters which we ignore.
+        let mut quality = 1000;
+        if let Some(pos) = media.params.iter().position(|(name, _)| {
+            name.eq_ignore_ascii_case("q")
+        }) {
+            quality = parse_quality(media.params[pos].1)?;
+            media.params.truncate(pos);
+        }
+        Some(MediaRange { media, quality })
+    }
+
+    /// Returns how specifically the range matches a media type.
+    ///
+    /// Returns `None` if the range doesn’t match at all. Otherwise a more
+    /// specific match results in a larger value.
+    fn specificity(&self, media: &MediaType) -> Option<usize> {
+        if self.media.ty == "*" {
+            return Some(0)
+        }
+        if !self.media.ty.eq_ignore_ascii_case(media.ty) {
+            return None
+        }
+        if self.media.subtype == "*" {
+            return Some(1)
+        }
+        if !self.media.subtype.eq_ignore_ascii_case(media.subtype) {
+            return None
+        }
+        for (name, value) in &self.media.params {
+            if !media.params.iter().any(|(offer_name, offer_value)| {
+                name.eq_ignore_ascii_case(offer_name)
+                    && value.eq_ignore_ascii_case(offer_value)
+            }) {
+                return None
+            }
+        }
+        Some(2 + self.media.params.len())
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Parses a quality value into thousandths.
+///
+/// Quality values are numbers between 0 and 1 with at most three
+/// decimal places.
+pub(crate) fn parse_quality(value: &str) -> Option<u16> {
+    let (int, frac) = match value.split_once('.') {
+        Some((int, frac)) => (int, frac),
+        None => (value, "")
+    };
+    if frac.len() > 3 || !frac.bytes().all(|ch| ch.is_ascii_digit()) {
+        return None
+    }
+    let mut res = match int {
+        "0" => 0,
+        "1" => 1000,
+        _ => return None
+    };
+    let mut factor = 100;
+    for ch in frac.bytes() {
+        res += u16::from(ch - b'0') * factor;
+        factor /= 10;
+    }
+    (res <= 1000).then_some(res)
+}
+
+/// Returns whether a string is a valid token.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|ch| {
+        ch.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&ch)
+    })
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn select(accept: Option<&'static str>) -> Option<usize> {
+        let mut headers = HeaderMap::new();
+        if let Some(accept) = accept {
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        }
+        select_content_type(
+            &headers,
+            &[ContentType::JSON, ContentType::CSV, ContentType::PROMETHEUS]
+        )
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.123"), Some(123));
+        assert_eq!(parse_quality("1.001"), None);
+        assert_eq!(parse_quality("0.1234"), None);
+        assert_eq!(parse_quality("2"), None);
+        assert_eq!(parse_quality(".5"), None);
+    }
+
+    #[test]
+    fn select_offers() {
+        assert_eq!(select(None), Some(0));
+        assert_eq!(select(Some("*/*")), Some(0));
+        assert_eq!(select(Some("text/csv")), Some(1));
+        assert_eq!(select(Some("text/*")), Some(1));
+        assert_eq!(select(Some("text/*, application/json;q=0.5")), Some(1));
+        assert_eq!(select(Some("text/*;q=0.1, */*;q=0.5")), Some(0));
+        assert_eq!(
+            select(Some("text/plain; version=0.0.4, */*;q=0.1")), Some(2)
+        );
+        assert_eq!(
+            select(Some("text/plain;version=0.0.5, text/html")), None
+        );
+        assert_eq!(select(Some("application/json;q=0, text/*")), Some(1));
+        assert_eq!(select(Some("image/png")), None);
+        assert_eq!(select(Some("TEXT/CSV;Q=0.9")), Some(1));
+        assert_eq!(select(Some("garbage")), Some(0));
+    }
+}
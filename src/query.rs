@@ -0,0 +1,509 @@
+//! Extracting typed values from query parameters.
+//!
+//! Types that can be created from the query parameters of a request
+//! implement [`FromQuery`]. They can then be extracted via
+//! [`RequestQuery::extract`]. Individual values are converted via the
+//! [`FromQueryValue`] trait.
+//!
+//! In addition, the set of acceptable parameters can be declared via a
+//! [`QuerySpec`] and checked via [`RequestQuery::check`].
+
+use std::{error, fmt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, Utc};
+use crate::request::RequestQuery;
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ FromQuery -----------------------------------------------------
+
+/// A type that can be created from query parameters.
+///
+/// Implementations take the values they need from the parser. The
+/// parser records any problems encountered along the way, so
+/// implementations only need to bail out via `?` on the values:
+///
+/// ```
+/// use httools::query::{FromQuery, QueryParser};
+///
+/// struct Params {
+///     asn: u32,
+///     limit: Option<usize>,
+///     tags: Vec<String>,
+/// }
+///
+/// impl FromQuery for Params {
+///     fn from_query(query: &mut QueryParser) -> Option<Self> {
+///         let asn = query.required("asn");
+///         let limit = query.optional("limit");
+///         let tags = query.all("tag");
+///         Some(Params { asn: asn?, limit: limit?, tags: tags? })
+///     }
+/// }
+/// ```
+///
+/// Note that all values are taken before the first `?` so that all
+/// problems are reported.
+pub trait FromQuery: Sized {
+    /// Creates a value from the query parameters.
+    ///
+    /// Returns `None` if any of the parameters were missing or invalid.
+    fn from_query(query: &mut QueryParser) -> Option<Self>;
+}
+
+
+//------------ FromQueryValue ------------------------------------------------
+
+/// A type that can be created from the value of a query parameter.
+pub trait FromQueryValue: Sized {
+    /// Converts the value.
+    ///
+    /// If the value cannot be converted, returns a message explaining why.
+    fn from_query_value(value: &str) -> Result<Self, String>;
+}
+
+macro_rules! from_str_impls {
+    ( $( $ty:ty ),* ) => {
+        $(
+            impl FromQueryValue for $ty {
+                fn from_query_value(value: &str) -> Result<Self, String> {
+                    value.parse().map_err(|err| format!("{}", err))
+                }
+            }
+        )*
+    }
+}
+
+from_str_impls!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize,
+    f32, f64, String, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr
+);
+
+/// Booleans accept `true`, `1`, `false`, `0`, and the empty value.
+///
+/// The empty value results in `true`, so `?verbose` works as a flag.
+impl FromQueryValue for bool {
+    fn from_query_value(value: &str) -> Result<Self, String> {
+        match value {
+            "" | "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err("expected a boolean".into())
+        }
+    }
+}
+
+/// Date and time values are expected in RFC 3339 format.
+#[cfg(feature = "chrono")]
+impl FromQueryValue for DateTime<Utc> {
+    fn from_query_value(value: &str) -> Result<Self, String> {
+        DateTime::parse_from_rfc3339(value).map(|date| {
+            date.with_timezone(&Utc)
+        }).map_err(|err| format!("{}", err))
+    }
+}
+
+
+//------------ QueryParser ---------------------------------------------------
+
+/// Takes typed values from query parameters while collecting problems.
+pub struct QueryParser<'a> {
+    query: &'a RequestQuery,
+    problems: Vec<QueryProblem>,
+}
+
+impl<'a> QueryParser<'a> {
+    pub(crate) fn new(query: &'a RequestQuery) -> Self {
+        QueryParser { query, problems: Vec::new() }
+    }
+
+    /// Returns the value of a parameter that must be present.
+    ///
+    /// If the parameter is given more than once, the first value is used.
+    /// If the parameter is missing or invalid, records the problem and
+    /// returns `None`.
+    pub fn required<T: FromQueryValue>(&mut self, key: &str) -> Option<T> {
+        match self.optional(key) {
+            Some(Some(value)) => Some(value),
+            Some(None) => {
+                self.push(key, QueryProblemKind::Missing);
+                None
+            }
+            None => None
+        }
+    }
+
+    /// Returns the value of a parameter that may be missing.
+    ///
+    /// Returns `Some(None)` if the parameter is missing. If the parameter
+    /// is invalid, records the problem and returns `None`.
+    pub fn optional<T: FromQueryValue>(
+        &mut self, key: &str
+    ) -> Option<Option<T>> {
+        match self.query.get_first(key) {
+            Some(value) => self.convert(key, value).map(Some),
+            None => Some(None)
+        }
+    }
+
+    /// Returns the value of a parameter or a default if it is missing.
+    pub fn or_default<T: FromQueryValue + Default>(
+        &mut self, key: &str
+    ) -> Option<T> {
+        self.optional(key).map(Option::unwrap_or_default)
+    }
+
+    /// Returns all values of a parameter.
+    ///
+    /// If the parameter is missing, returns an empty vec. If any value is
+    /// invalid, records the problem and returns `None`.
+    pub fn all<T: FromQueryValue>(&mut self, key: &str) -> Option<Vec<T>> {
+        let values = match self.query.get(key) {
+            Some(values) => values,
+            None => return Some(Vec::new())
+        };
+        let mut res = Some(Vec::with_capacity(values.len()));
+        for value in values {
+            match self.convert(key, value) {
+                Some(value) => {
+                    if let Some(res) = res.as_mut() {
+                        res.push(value)
+                    }
+                }
+                None => res = None
+            }
+        }
+        res
+    }
+
+    /// Records a problem with a parameter.
+    ///
+    /// This can be used by [`FromQuery`] implementations to report
+    /// problems of their own, such as values that conflict.
+    pub fn invalid(&mut self, key: &str, msg: impl Into<String>) {
+        self.push(key, QueryProblemKind::Invalid(msg.into()))
+    }
+
+    fn convert<T: FromQueryValue>(
+        &mut self, key: &str, value: &str
+    ) -> Option<T> {
+        match T::from_query_value(value) {
+            Ok(value) => Some(value),
+            Err(msg) => {
+                self.invalid(key, msg);
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, key: &str, kind: QueryProblemKind) {
+        self.problems.push(QueryProblem::new(key, kind))
+    }
+
+    /// Finishes parsing.
+    pub(crate) fn finish<T>(
+        self, res: Option<T>
+    ) -> Result<T, QueryError> {
+        match res {
+            Some(res) if self.problems.is_empty() => Ok(res),
+            _ => Err(QueryError { problems: self.problems })
+        }
+    }
+}
+
+
+//------------ QuerySpec -----------------------------------------------------
+
+/// A declaration of the acceptable query parameters.
+///
+/// ```
+/// use httools::query::QuerySpec;
+///
+/// let spec = QuerySpec::new().single("asn").multi("tag").flag("verbose");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct QuerySpec {
+    keys: Vec<KeySpec>,
+}
+
+#[derive(Clone, Debug)]
+struct KeySpec {
+    key: String,
+
+    /// Whether the parameter may be given more than once.
+    multi: bool,
+
+    /// Whether the parameter may have an empty value.
+    empty: bool,
+}
+
+impl QuerySpec {
+    /// Creates a spec that doesn’t allow any parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows a parameter that may be given at most once.
+    pub fn single(self, key: impl Into<String>) -> Self {
+        self.add(key.into(), false, false)
+    }
+
+    /// Allows a parameter that may be given multiple times.
+    pub fn multi(self, key: impl Into<String>) -> Self {
+        self.add(key.into(), true, false)
+    }
+
+    /// Allows a parameter that may be given once with an empty value.
+    pub fn flag(self, key: impl Into<String>) -> Self {
+        self.add(key.into(), false, true)
+    }
+
+    fn add(mut self, key: String, multi: bool, empty: bool) -> Self {
+        self.keys.push(KeySpec { key, multi, empty });
+        self
+    }
+
+    /// Checks the parameters against the spec.
+    ///
+    /// Reports unknown parameters, parameters that are given more than
+    /// once even though they are single-valued, and empty values for
+    /// parameters other than flags. The problems are ordered by key.
+    pub(crate) fn check<'a>(
+        &self, params: impl Iterator<Item = (&'a str, &'a [String])>
+    ) -> Result<(), QueryError> {
+        let mut problems = Vec::new();
+        for (key, values) in params {
+            let spec = match self.keys.iter().find(|spec| spec.key == key) {
+                Some(spec) => spec,
+                None => {
+                    problems.push(QueryProblem::new(
+                        key, QueryProblemKind::Unknown
+                    ));
+                    continue;
+                }
+            };
+            if !spec.multi && values.len() > 1 {
+                problems.push(QueryProblem::new(
+                    key, QueryProblemKind::Duplicate
+                ));
+            }
+            if !spec.empty && values.iter().any(String::is_empty) {
+                problems.push(QueryProblem::new(
+                    key, QueryProblemKind::Empty
+                ));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        }
+        else {
+            problems.sort_by(|left, right| left.key.cmp(&right.key));
+            Err(QueryError { problems })
+        }
+    }
+}
+
+
+//------------ QueryError ----------------------------------------------------
+
+/// The query parameters of a request were not acceptable.
+///
+/// The error can be converted into a Bad Request response listing all
+/// problems.
+#[derive(Clone, Debug)]
+pub struct QueryError {
+    problems: Vec<QueryProblem>,
+}
+
+impl QueryError {
+    /// Returns the problems with the parameters.
+    pub fn problems(&self) -> &[QueryProblem] {
+        &self.problems
+    }
+
+    /// Converts the error into a Bad Request response.
+    pub fn into_response(self) -> Response {
+        ResponseBuilder::new().bad_request()
+            .content_type(ContentType::TEXT)
+            .body(self.to_string())
+    }
+}
+
+impl From<QueryError> for Response {
+    fn from(err: QueryError) -> Self {
+        err.into_response()
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.problems.is_empty() {
+            return f.write_str("Invalid query parameters.\n")
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for QueryError { }
+
+
+//------------ QueryProblem --------------------------------------------------
+
+/// A problem with a single query parameter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryProblem {
+    key: String,
+    kind: QueryProblemKind,
+}
+
+impl QueryProblem {
+    fn new(key: &str, kind: QueryProblemKind) -> Self {
+        QueryProblem { key: key.into(), kind }
+    }
+
+    /// Returns the name of the parameter.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns what is wrong with the parameter.
+    pub fn kind(&self) -> &QueryProblemKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for QueryProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            QueryProblemKind::Missing => {
+                write!(f, "Missing query parameter '{}'.", self.key)
+            }
+            QueryProblemKind::Invalid(ref msg) => {
+                write!(f, "Invalid query parameter '{}': {}", self.key, msg)
+            }
+            QueryProblemKind::Unknown => {
+                write!(f, "Unknown query parameter '{}'.", self.key)
+            }
+            QueryProblemKind::Duplicate => {
+                write!(
+                    f, "Query parameter '{}' given more than once.", self.key
+                )
+            }
+            QueryProblemKind::Empty => {
+                write!(f, "Empty query parameter '{}'.", self.key)
+            }
+        }
+    }
+}
+
+
+//------------ QueryProblemKind ----------------------------------------------
+
+/// What is wrong with a query parameter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum QueryProblemKind {
+    /// A required parameter is missing.
+    Missing,
+
+    /// The value of the parameter could not be converted.
+    Invalid(String),
+
+    /// The parameter is not allowed.
+    Unknown,
+
+    /// A single-valued parameter was given more than once.
+    Duplicate,
+
+    /// The parameter has an empty value.
+    Empty,
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::Body;
+    use crate::request::Request;
+
+    #[derive(Debug)]
+    struct Params {
+        asn: u32,
+        limit: Option<usize>,
+        verbose: bool,
+        tags: Vec<String>,
+    }
+
+    impl FromQuery for Params {
+        fn from_query(query: &mut QueryParser) -> Option<Self> {
+            let asn = query.required("asn");
+            let limit = query.optional("limit");
+            let verbose = query.or_default("verbose");
+            let tags = query.all("tag");
+            Some(Params {
+                asn: asn?, limit: limit?, verbose: verbose?, tags: tags?
+            })
+        }
+    }
+
+    fn request(uri: &str) -> Request {
+        Request::from_hyper(
+            hyper::Request::builder().uri(uri).body(Body::empty()).unwrap()
+        )
+    }
+
+    fn extract(uri: &str) -> Result<Params, QueryError> {
+        request(uri).query().extract()
+    }
+
+    #[test]
+    fn extract_params() {
+        let params = extract("/?asn=64496&verbose&tag=a&tag=b").unwrap();
+        assert_eq!(params.asn, 64496);
+        assert_eq!(params.limit, None);
+        assert!(params.verbose);
+        assert_eq!(params.tags, ["a", "b"]);
+
+        let params = extract("/?asn=64496&limit=10&verbose=0").unwrap();
+        assert_eq!(params.limit, Some(10));
+        assert!(!params.verbose);
+
+        let err = extract("/?limit=ten&verbose=maybe").unwrap_err();
+        assert_eq!(
+            err.problems().iter().map(|item| {
+                (item.key(), matches!(item.kind(), QueryProblemKind::Missing))
+            }).collect::<Vec<_>>(),
+            [("asn", true), ("limit", false), ("verbose", false)]
+        );
+        let resp = Response::from(err).into_hyper();
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn check() {
+        let spec = QuerySpec::new().single("asn").multi("tag").flag("v");
+        let check = |uri| {
+            request(uri).query().check(&spec).map_err(|err| {
+                err.problems().iter().map(|item| {
+                    (item.key().to_string(), item.kind().clone())
+                }).collect::<Vec<_>>()
+            })
+        };
+
+        assert!(check("/?asn=1&tag=a&tag=b&v").is_ok());
+        assert!(check("/").is_ok());
+        assert_eq!(
+            check("/?asn=1&asn=2&tag=&v&x=1&asm=3").unwrap_err(),
+            [
+                ("asm".into(), QueryProblemKind::Unknown),
+                ("asn".into(), QueryProblemKind::Duplicate),
+                ("tag".into(), QueryProblemKind::Empty),
+                ("x".into(), QueryProblemKind::Unknown),
+            ]
+        );
+    }
+}
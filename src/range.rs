@@ -0,0 +1,386 @@
+//! Range requests.
+//!
+//! This module implements byte range requests as described in RFC 9110,
+//! section 14. Use [`RangeRequest::evaluate`] to determine which part of
+//! a representation to return.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::Method;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HeaderValue, RANGE};
+use crate::conditional::Preconditions;
+use crate::request::Request;
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ RangeRequest --------------------------------------------------
+
+/// What part of a representation a request asks for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RangeRequest {
+    /// The complete representation should be returned.
+    Full,
+
+    /// The given ranges of the representation should be returned.
+    ///
+    /// The ranges are non-empty, sorted, don’t overlap, and lie within
+    /// the representation.
+    Partial(Vec<Range<u64>>),
+
+    /// None of the requested ranges can be satisfied.
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// The maximum number of ranges we accept in a request.
+    ///
+    /// If there are more, we ignore the Range header and return the full
+    /// representation.
+    const MAX_RANGES: usize = 32;
+
+    /// Evaluates the Range header of a request.
+    ///
+    /// `len` is the length of the complete representation. The range is
+    /// only honoured for GET requests and if the If-Range condition is
+    /// true as determined by `cond`. A malformed Range header or one with
+    /// a unit other than bytes is ignored.
+    pub fn evaluate(
+        request: &Request, cond: &Preconditions, len: u64
+    ) -> Self {
+        if request.method() != Method::GET || !cond.if_range(request) {
+            return RangeRequest::Full
+        }
+        match request.headers().get(RANGE).and_then(|value| {
+            value.to_str().ok()
+        }) {
+            Some(value) => Self::from_header(value, len),
+            None => RangeRequest::Full,
+        }
+    }
+
+    /// Evaluates the value of a Range header.
+    pub fn from_header(value: &str, len: u64) -> Self {
+        let specs = match parse_range(value) {
+            Some(specs) if specs.len() <= Self::MAX_RANGES => specs,
+            _ => return RangeRequest::Full,
+        };
+        let mut ranges: Vec<_> = specs.into_iter().filter_map(|spec| {
+            spec.resolve(len)
+        }).collect();
+        if ranges.is_empty() {
+            return RangeRequest::Unsatisfiable
+        }
+
+        // Coalesce overlapping and adjacent ranges.
+        ranges.sort_by_key(|range| range.start);
+        let mut res: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match res.last_mut() {
+                Some(last) if range.start <= last.end => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => res.push(range)
+            }
+        }
+        RangeRequest::Partial(res)
+    }
+
+    /// Creates the response for a representation held in memory.
+    ///
+    /// The builder should contain all headers describing the
+    /// representation such as its ETag. The status, content type, and
+    /// the headers related to ranges are added by this method.
+    pub fn respond(
+        self,
+        builder: ResponseBuilder,
+        content_type: ContentType,
+        data: Bytes,
+    ) -> Response {
+        let len = data.len() as u64;
+        match self {
+            RangeRequest::Full => {
+                builder.ok().accept_ranges()
+                    .content_type(content_type)
+                    .body(data)
+            }
+            RangeRequest::Partial(ranges) => {
+                let parts = ranges.into_iter().map(|range| {
+                    let part = data.slice(
+                        range.start as usize..range.end as usize
+                    );
+                    (range, part)
+                }).collect();
+                partial_response(builder, content_type, len, parts)
+            }
+            RangeRequest::Unsatisfiable => {
+                Response::range_not_satisfiable(len)
+            }
+        }
+    }
+}
+
+
+//------------ partial_response ----------------------------------------------
+
+/// Creates a 206 Partial Content response from the given parts.
+///
+/// `len` is the length of the complete representation and `parts` the
+/// ranges to return together with their content. A single part is
+/// returned directly, multiple parts as a `multipart/byteranges` body.
+///
+/// # Panics
+///
+/// The function panics if `parts` is empty.
+pub fn partial_response(
+    builder: ResponseBuilder,
+    content_type: ContentType,
+    len: u64,
+    parts: Vec<(Range<u64>, Bytes)>,
+) -> Response {
+    assert!(!parts.is_empty(), "no parts for partial response");
+    let builder = builder.partial_content().accept_ranges();
+    if parts.len() == 1 {
+        let (range, data) = parts.into_iter().next().unwrap();
+        return builder.content_type(content_type)
+            .content_range(range, len)
+            .body(data)
+    }
+
+    let boundary = boundary();
+    let mut body = Vec::new();
+    for (range, data) in parts {
+        body.extend_from_slice(b"--");
+        body.extend_from_slice(boundary.as_bytes());
+        body.extend_from_slice(b"\r\nContent-Type: ");
+        body.extend_from_slice(content_type.as_str().as_bytes());
+        body.extend_from_slice(
+            format!(
+                "\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                range.start, range.end - 1, len
+            ).as_bytes()
+        );
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"--");
+    body.extend_from_slice(boundary.as_bytes());
+    body.extend_from_slice(b"--\r\n");
+    builder.header(
+        CONTENT_TYPE,
+        HeaderValue::from_str(
+            &format!("multipart/byteranges; boundary={}", boundary)
+        ).expect("invalid boundary")
+    ).body(body)
+}
+
+/// Creates a boundary for a multipart body.
+///
+/// The boundary only needs to be unlikely to appear in the content.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| {
+        time.as_nanos() as u64
+    }).unwrap_or(0);
+    format!(
+        "httools-{:016x}-{:x}",
+        time, COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+
+//------------ RangeSpec -----------------------------------------------------
+
+/// A single range from a Range header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RangeSpec {
+    /// The range from the first to the last position, both inclusive.
+    FromTo(u64, u64),
+
+    /// The range from the given position to the end.
+    From(u64),
+
+    /// The given number of bytes at the end.
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    /// Resolves the range for a representation of the given length.
+    ///
+    /// Returns `None` if the range is not satisfiable.
+    fn resolve(self, len: u64) -> Option<Range<u64>> {
+        match self {
+            RangeSpec::FromTo(first, last) => {
+                (first < len).then(|| first..last.saturating_add(1).min(len))
+            }
+            RangeSpec::From(first) => (first < len).then_some(first..len),
+            RangeSpec::Suffix(suffix) => {
+                (suffix > 0 && len > 0).then(|| {
+                    len.saturating_sub(suffix)..len
+                })
+            }
+        }
+    }
+}
+
+/// Parses the value of a Range header.
+///
+/// Returns `None` if the value is malformed or uses a unit other than
+/// bytes.
+fn parse_range(value: &str) -> Option<Vec<RangeSpec>> {
+    let (unit, ranges) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None
+    }
+    let mut res = Vec::new();
+    for range in ranges.split(',') {
+        let range = range.trim_matches([' ', '\t']);
+        if range.is_empty() {
+            continue
+        }
+        let (first, last) = range.split_once('-')?;
+        res.push(
+            if first.is_empty() {
+                RangeSpec::Suffix(parse_pos(last)?)
+            }
+            else if last.is_empty() {
+                RangeSpec::From(parse_pos(first)?)
+            }
+            else {
+                let first = parse_pos(first)?;
+                let last = parse_pos(last)?;
+                if last < first {
+                    return None
+                }
+                RangeSpec::FromTo(first, last)
+            }
+        )
+    }
+    if res.is_empty() {
+        None
+    }
+    else {
+        Some(res)
+    }
+}
+
+/// Parses a position in a range.
+///
+/// Unlike `u64::from_str`, this only accepts digits.
+fn parse_pos(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|ch| ch.is_ascii_digit()) {
+        return None
+    }
+    value.parse().ok()
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{Body, StatusCode};
+    use crate::etag::ETag;
+
+    fn ranges(value: &str) -> RangeRequest {
+        RangeRequest::from_header(value, 100)
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn from_header() {
+        use RangeRequest::*;
+
+        assert_eq!(ranges("bytes=0-9"), Partial(vec![0..10]));
+        assert_eq!(ranges("Bytes = 10-"), Partial(vec![10..100]));
+        assert_eq!(ranges("bytes=-10"), Partial(vec![90..100]));
+        assert_eq!(ranges("bytes=-200"), Partial(vec![0..100]));
+        assert_eq!(ranges("bytes=90-200"), Partial(vec![90..100]));
+        assert_eq!(
+            ranges("bytes=50-59, 0-9"), Partial(vec![0..10, 50..60])
+        );
+        assert_eq!(
+            ranges("bytes=0-9,5-19,20-29,-5"), Partial(vec![0..30, 95..100])
+        );
+        assert_eq!(ranges("bytes=100-"), Unsatisfiable);
+        assert_eq!(ranges("bytes=-0"), Unsatisfiable);
+        assert_eq!(ranges("bytes=100-200, 150-"), Unsatisfiable);
+        assert_eq!(ranges("bytes=10-5"), Full);
+        assert_eq!(ranges("bytes=a-5"), Full);
+        assert_eq!(ranges("bytes=+1-5"), Full);
+        assert_eq!(ranges("bytes="), Full);
+        assert_eq!(ranges("items=0-9"), Full);
+        assert_eq!(
+            RangeRequest::from_header("bytes=-10", 0), Unsatisfiable
+        );
+    }
+
+    async fn body(response: hyper::Response<Body>) -> String {
+        String::from_utf8(
+            hyper::body::to_bytes(response.into_body()).await.unwrap().into()
+        ).unwrap()
+    }
+
+    #[tokio::test]
+    async fn respond() {
+        let data = Bytes::from_static(b"0123456789");
+        let cond = Preconditions::new().etag(ETag::strong("v1").unwrap());
+        let respond = |range: Option<&str>, if_range: Option<&str>| {
+            let mut request = hyper::Request::builder();
+            if let Some(range) = range {
+                request = request.header("Range", range);
+            }
+            if let Some(if_range) = if_range {
+                request = request.header("If-Range", if_range);
+            }
+            let request = Request::from_hyper(
+                request.body(Body::empty()).unwrap()
+            );
+            RangeRequest::evaluate(&request, &cond, 10).respond(
+                ResponseBuilder::new(), ContentType::TEXT, data.clone()
+            ).into_hyper()
+        };
+
+        let resp = respond(None, None);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Accept-Ranges"], "bytes");
+        assert_eq!(body(resp).await, "0123456789");
+
+        let resp = respond(Some("bytes=2-4"), Some("\"v1\""));
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()["Content-Range"], "bytes 2-4/10");
+        assert_eq!(body(resp).await, "234");
+
+        let resp = respond(Some("bytes=2-4"), Some("\"v0\""));
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = respond(Some("bytes=20-"), None);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()["Content-Range"], "bytes */10");
+
+        let resp = respond(Some("bytes=0-1,-2"), None);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = resp.headers()["Content-Type"].to_str().unwrap();
+        let boundary = content_type.strip_prefix(
+            "multipart/byteranges; boundary="
+        ).unwrap().to_string();
+        assert_eq!(
+            body(resp).await,
+            format!(
+                "--{b}\r\n\
+                Content-Type: text/plain;charset=utf-8\r\n\
+                Content-Range: bytes 0-1/10\r\n\r\n\
+                01\r\n\
+                --{b}\r\n\
+                Content-Type: text/plain;charset=utf-8\r\n\
+                Content-Range: bytes 8-9/10\r\n\r\n\
+                89\r\n\
+                --{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+}
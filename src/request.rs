@@ -3,12 +3,19 @@
 use std::{mem, slice};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use hyper::{Body, Uri};
-use hyper::header::{HeaderMap, HeaderValue};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use hyper::{Body, Method, Uri, Version};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue};
+use hyper::http::Extensions;
 use hyper::http::uri::PathAndQuery;
 use url::form_urlencoded;
 use url::percent_encoding::percent_decode;
-use super::response::Response;
+use super::multipart::{self, MultipartLimits, MultipartSink};
+use super::negotiate::select_content_type;
+use super::query::{FromQuery, QueryError, QueryParser, QuerySpec};
+use super::response::{ContentType, Response, ResponseBuilder};
+use super::router::PathParams;
 
 
 //------------ Request -------------------------------------------------------
@@ -22,6 +29,11 @@ impl Request {
         Request(request)
     }
 
+    /// Returns the method of the request.
+    pub fn method(&self) -> &Method {
+        self.0.method()
+    }
+
     pub fn uri(&self) -> &Uri {
         self.0.uri()
     }
@@ -41,19 +53,334 @@ impl Request {
     pub fn headers(&self) -> &HeaderMap<HeaderValue> {
         self.0.headers()
     }
+
+    /// Returns the parameters extracted from the path by a router.
+    ///
+    /// If the request was not dispatched via a [`Router`], the returned
+    /// value is empty.
+    ///
+    /// [`Router`]: crate::router::Router
+    pub fn params(&self) -> &PathParams {
+        static EMPTY: PathParams = PathParams::EMPTY;
+        self.0.extensions().get().unwrap_or(&EMPTY)
+    }
+
+    /// Returns the HTTP version of the request.
+    pub fn version(&self) -> Version {
+        self.0.version()
+    }
+
+    /// Returns the extensions of the request.
+    pub fn extensions(&self) -> &Extensions {
+        self.0.extensions()
+    }
+
+    /// Returns a mutable reference to the extensions of the request.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.0.extensions_mut()
+    }
+
+    /// Returns information about the connection the request arrived on.
+    ///
+    /// Returns `None` if the request was not received through a server
+    /// started via the [`server`][crate::server] module.
+    pub fn connection(&self) -> Option<&ConnectionInfo> {
+        self.0.extensions().get()
+    }
+
+    /// Returns the address of the peer that sent the request.
+    pub fn peer_addr(&self) -> Option<PeerAddr> {
+        self.connection().map(ConnectionInfo::peer)
+    }
+
+    /// Returns the local address the request was received on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.connection()?.local_addr()
+    }
+
+    /// Returns whether the request was received over TLS.
+    pub fn is_tls(&self) -> bool {
+        self.connection().map(ConnectionInfo::is_tls).unwrap_or(false)
+    }
+
+    /// Returns the IP address of the client that sent the request.
+    ///
+    /// If the peer is one of the trusted proxies configured for the
+    /// server, the address is taken from the `Forwarded` or
+    /// `X-Forwarded-For` header. Otherwise it is the address of the peer.
+    ///
+    /// Returns `None` if the address is not known. This happens if a
+    /// request was received via a Unix socket without forwarding
+    /// information or if a proxy didn’t know the address.
+    pub fn client_addr(&self) -> Option<IpAddr> {
+        self.0.extensions().get::<ClientAddr>()?.0
+    }
 }
 
 impl Request {
+    /// Requires the request to be a GET request.
+    ///
+    /// HEAD requests are accepted, too. See
+    /// [`require_method`][Self::require_method] for details.
     pub fn require_get(&self) -> Result<(), Response> {
-        if self.0.method() != hyper::Method::GET {
-            Err(Response::method_not_allowed())
+        self.require_method(&[Method::GET])
+    }
+
+    /// Requires the request to use one of the given methods.
+    ///
+    /// If `methods` contains GET, a HEAD request is accepted as well. The
+    /// server takes care of removing the body from the response to a HEAD
+    /// request.
+    ///
+    /// If the method isn’t acceptable, returns a Method Not Allowed
+    /// response with the Allow header listing the methods.
+    pub fn require_method(&self, methods: &[Method]) -> Result<(), Response> {
+        if is_allowed(self.method(), methods) {
+            Ok(())
         }
         else {
-            Ok(())
+            Err(Response::method_not_allowed_with(methods))
+        }
+    }
+}
+
+/// Returns whether `method` is one of `methods`, allowing HEAD for GET.
+pub(crate) fn is_allowed(method: &Method, methods: &[Method]) -> bool {
+    methods.contains(method)
+        || (method == Method::HEAD && methods.contains(&Method::GET))
+}
+
+impl Request {
+    /// Selects the content type of the response.
+    ///
+    /// Picks the best of the `offers` according to the Accept header of
+    /// the request. Earlier offers are preferred if the client likes
+    /// several equally well. If there is no Accept header, the first
+    /// offer is selected. If none of the offers is acceptable, returns a
+    /// Not Acceptable response.
+    ///
+    /// Responses should include a `Vary: Accept` header, e.g., via
+    /// [`ResponseBuilder::vary`].
+    pub fn negotiate(
+        &self, offers: &[ContentType]
+    ) -> Result<ContentType, Response> {
+        match select_content_type(self.headers(), offers) {
+            Some(idx) => Ok(offers[idx].clone()),
+            None => Err(Response::not_acceptable(offers))
         }
     }
 }
 
+impl Request {
+    /// Reads the entire request body.
+    ///
+    /// If the body is larger than `limit` bytes, returns a Payload Too
+    /// Large response. If reading the body fails, returns a Bad Request
+    /// response.
+    ///
+    /// The body is taken out of the request, so a second call will return
+    /// an empty body.
+    pub async fn body_bytes(
+        &mut self, limit: usize
+    ) -> Result<Bytes, Response> {
+        // Reject early if the announced length is too large already.
+        if let Some(len) = self.headers().get(CONTENT_LENGTH) {
+            let len = len.to_str().ok().and_then(|len| {
+                len.parse::<u64>().ok()
+            }).ok_or_else(Response::bad_request)?;
+            if len > limit as u64 {
+                return Err(Response::payload_too_large())
+            }
+        }
+
+        let mut body = mem::take(self.0.body_mut());
+        let mut res = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|_| Response::bad_request())?;
+            if res.len() + chunk.len() > limit {
+                return Err(Response::payload_too_large())
+            }
+            res.extend_from_slice(&chunk);
+        }
+        Ok(res.into())
+    }
+
+    /// Reads the entire request body into a string.
+    ///
+    /// This is the same as [`body_bytes`][Self::body_bytes] but also
+    /// returns a Bad Request response if the body is not valid UTF-8.
+    pub async fn body_string(
+        &mut self, limit: usize
+    ) -> Result<String, Response> {
+        String::from_utf8(
+            self.body_bytes(limit).await?.into()
+        ).map_err(|_| {
+            ResponseBuilder::new().bad_request()
+                .content_type(ContentType::TEXT)
+                .body("Request body is not valid UTF-8.")
+        })
+    }
+
+    /// Reads a URL-encoded form from the request body.
+    ///
+    /// The request must have the content type
+    /// `application/x-www-form-urlencoded` or an Unsupported Media Type
+    /// response is returned. The body must not be larger than `limit`
+    /// bytes or a Payload Too Large response is returned.
+    ///
+    /// The form fields are returned the same way as query parameters.
+    pub async fn form(
+        &mut self, limit: usize
+    ) -> Result<RequestQuery, Response> {
+        if !self.media_type().is_some_and(|ty| {
+            ty.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        }) {
+            return Err(unsupported_media_type(
+                "application/x-www-form-urlencoded"
+            ))
+        }
+        Ok(RequestQuery::from_form(&self.body_bytes(limit).await?))
+    }
+
+    /// Reads a `multipart/form-data` form from the request body.
+    ///
+    /// The request must have the content type `multipart/form-data` with
+    /// a boundary parameter or an Unsupported Media Type response is
+    /// returned.
+    ///
+    /// Text fields are returned the same way as query parameters. Parts
+    /// with a file name are handed to `sink` instead which provides
+    /// a writer for their content. The body is processed as it arrives,
+    /// so files are never kept in memory as a whole. If any of the limits
+    /// is exceeded, a Payload Too Large response is returned; if the body
+    /// is malformed, a Bad Request response.
+    pub async fn multipart(
+        &mut self, limits: MultipartLimits, sink: impl MultipartSink
+    ) -> Result<RequestQuery, Response> {
+        if !self.media_type().is_some_and(|ty| {
+            ty.eq_ignore_ascii_case("multipart/form-data")
+        }) {
+            return Err(unsupported_media_type("multipart/form-data"))
+        }
+        let boundary = self.headers().get(CONTENT_TYPE).and_then(|value| {
+            split_unquoted(value.to_str().ok()?, ';').skip(1).find_map(
+                |param| {
+                    let (key, value) = param.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("boundary").then(|| {
+                        let value = value.trim();
+                        value.strip_prefix('"').and_then(|value| {
+                            value.strip_suffix('"')
+                        }).unwrap_or(value).to_string()
+                    })
+                }
+            )
+        });
+        let boundary = match boundary {
+            Some(boundary) if multipart::is_valid_boundary(&boundary) => {
+                boundary
+            }
+            _ => {
+                return Err(
+                    ResponseBuilder::new().bad_request()
+                        .content_type(ContentType::TEXT)
+                        .body("Missing or invalid multipart boundary.")
+                )
+            }
+        };
+        multipart::parse(
+            mem::take(self.0.body_mut()), &boundary, limits, sink
+        ).await
+    }
+
+    /// Returns the media type of the request body without parameters.
+    fn media_type(&self) -> Option<&str> {
+        let value = self.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+        Some(value.split(';').next().unwrap_or("").trim())
+    }
+}
+
+/// Creates the response for a request body of the wrong type.
+fn unsupported_media_type(expected: &str) -> Response {
+    ResponseBuilder::new().unsupported_media_type()
+        .content_type(ContentType::TEXT)
+        .body(format!("Expected content type {}.", expected))
+}
+
+#[cfg(feature = "json")]
+impl Request {
+    /// Reads the request body as JSON and deserializes it.
+    ///
+    /// The request must have a JSON content type, i.e., either
+    /// `application/json` or a type with a `+json` suffix. Otherwise
+    /// returns an Unsupported Media Type response. The body must not be
+    /// larger than `limit` bytes or a Payload Too Large response is
+    /// returned.
+    ///
+    /// If the body cannot be deserialized, returns a Bad Request response
+    /// with a JSON document describing the error. Its `path` member
+    /// points to the offending field, its `line` and `column` members to
+    /// the position in the body.
+    pub async fn json<T: serde::de::DeserializeOwned>(
+        &mut self, limit: usize
+    ) -> Result<T, Response> {
+        use crate::json::JsonBuilder;
+
+        if !self.has_json_content_type() {
+            return Err(
+                ResponseBuilder::new().unsupported_media_type()
+                    .content_type(ContentType::JSON)
+                    .body(JsonBuilder::build(|json| json.object(|json| {
+                        json.string("error", "unsupported media type");
+                        json.string(
+                            "message",
+                            "expected content type application/json"
+                        );
+                    })))
+            )
+        }
+        let body = self.body_bytes(limit).await?;
+        let mut de = serde_json::Deserializer::from_slice(&body);
+        let res = serde_path_to_error::deserialize(&mut de).map_err(|err| {
+            let path = err.path().to_string();
+            invalid_json(&err.into_inner(), &path)
+        })?;
+        de.end().map_err(|err| invalid_json(&err, "."))?;
+        Ok(res)
+    }
+
+    /// Returns whether the request has a JSON content type.
+    fn has_json_content_type(&self) -> bool {
+        let media_type = match self.media_type() {
+            Some(media_type) => media_type,
+            None => return false
+        };
+        let (ty, subtype) = match media_type.split_once('/') {
+            Some(some) => some,
+            None => return false
+        };
+        ty.eq_ignore_ascii_case("application") && (
+            subtype.eq_ignore_ascii_case("json")
+            || subtype.to_ascii_lowercase().ends_with("+json")
+        )
+    }
+}
+
+/// Creates the response for a request body that isn’t acceptable JSON.
+#[cfg(feature = "json")]
+fn invalid_json(err: &serde_json::Error, path: &str) -> Response {
+    use crate::json::JsonBuilder;
+
+    ResponseBuilder::new().bad_request()
+        .content_type(ContentType::JSON)
+        .body(JsonBuilder::build(|json| json.object(|json| {
+            json.string("error", "invalid JSON");
+            json.string("message", err);
+            json.string("path", path);
+            json.number("line", err.line());
+            json.number("column", err.column());
+        })))
+}
+
 impl From<hyper::Request<Body>> for Request {
     fn from(src: hyper::Request<Body>) -> Self {
         Self::from_hyper(src)
@@ -61,6 +388,227 @@ impl From<hyper::Request<Body>> for Request {
 }
 
 
+//------------ ConnectionInfo ------------------------------------------------
+
+/// Information about the connection a request was received on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    peer: PeerAddr,
+    local: Option<SocketAddr>,
+    tls: bool,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(
+        peer: PeerAddr, local: Option<SocketAddr>, tls: bool
+    ) -> Self {
+        ConnectionInfo { peer, local, tls }
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn with_tls(self) -> Self {
+        ConnectionInfo { tls: true, ..self }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer(&self) -> PeerAddr {
+        self.peer
+    }
+
+    /// Returns the local address if the connection is a TCP connection.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local
+    }
+
+    /// Returns whether the connection uses TLS.
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+}
+
+
+//------------ PeerAddr ------------------------------------------------------
+
+/// The address of the peer of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PeerAddr {
+    /// The peer connected via TCP from the given address.
+    Tcp(SocketAddr),
+
+    /// The peer connected via a Unix domain socket.
+    Unix,
+}
+
+impl PeerAddr {
+    /// Returns the socket address if the peer connected via TCP.
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match *self {
+            PeerAddr::Tcp(addr) => Some(addr),
+            PeerAddr::Unix => None,
+        }
+    }
+
+    /// Returns whether the peer connected via a Unix domain socket.
+    pub fn is_unix(&self) -> bool {
+        matches!(*self, PeerAddr::Unix)
+    }
+}
+
+
+//------------ TrustedProxies ------------------------------------------------
+
+/// The reverse proxies whose forwarding headers we believe.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies {
+    /// The addresses of trusted proxies.
+    addrs: Vec<IpAddr>,
+
+    /// Whether peers connecting via a Unix socket are trusted.
+    unix: bool,
+}
+
+impl TrustedProxies {
+    pub(crate) fn add(&mut self, addr: IpAddr) {
+        self.addrs.push(addr.to_canonical())
+    }
+
+    pub(crate) fn set_unix(&mut self, unix: bool) {
+        self.unix = unix
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.addrs.contains(&addr)
+    }
+
+    /// Determines the client address of a request.
+    ///
+    /// Walks the forwarding chain from the peer backwards for as long as
+    /// the addresses are trusted proxies. IPv4-mapped IPv6 addresses, as
+    /// seen on dual-stack sockets, are converted to IPv4 addresses first.
+    pub(crate) fn client_addr(
+        &self, peer: PeerAddr, headers: &HeaderMap
+    ) -> ClientAddr {
+        let mut res = match peer {
+            PeerAddr::Tcp(addr) => {
+                let addr = addr.ip().to_canonical();
+                if !self.is_trusted(addr) {
+                    return ClientAddr(Some(addr))
+                }
+                Some(addr)
+            }
+            PeerAddr::Unix => {
+                if !self.unix {
+                    return ClientAddr(None)
+                }
+                None
+            }
+        };
+        for hop in forwarded_for(headers).into_iter().rev() {
+            res = hop.map(|addr| addr.to_canonical());
+            match hop {
+                Some(addr) if self.is_trusted(addr) => { }
+                _ => break
+            }
+        }
+        ClientAddr(res)
+    }
+}
+
+
+//------------ ClientAddr ----------------------------------------------------
+
+/// The client address of a request as determined by the server.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientAddr(Option<IpAddr>);
+
+
+//------------ Parsing Forwarding Headers ------------------------------------
+
+/// Returns the forwarding chain of a request.
+///
+/// Uses the `Forwarded` header if present and `X-Forwarded-For`
+/// otherwise. Entries with an unknown or obfuscated address are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut res = Vec::new();
+    if headers.contains_key("Forwarded") {
+        for value in headers.get_all("Forwarded") {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => {
+                    res.push(None);
+                    continue
+                }
+            };
+            for element in split_unquoted(value, ',') {
+                res.push(
+                    split_unquoted(element, ';').find_map(|pair| {
+                        let (key, value) = pair.split_once('=')?;
+                        if key.trim().eq_ignore_ascii_case("for") {
+                            Some(parse_node(value.trim()))
+                        }
+                        else {
+                            None
+                        }
+                    }).flatten()
+                )
+            }
+        }
+    }
+    else {
+        for value in headers.get_all("X-Forwarded-For") {
+            match value.to_str() {
+                Ok(value) => {
+                    res.extend(value.split(',').map(|item| {
+                        parse_node(item.trim())
+                    }))
+                }
+                Err(_) => res.push(None)
+            }
+        }
+    }
+    res
+}
+
+/// Splits a header value at `sep` outside of quoted strings.
+pub(crate) fn split_unquoted(
+    value: &str, sep: char
+) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    value.split(move |ch| {
+        if escaped {
+            escaped = false
+        }
+        else if quoted && ch == '\\' {
+            escaped = true
+        }
+        else if ch == '"' {
+            quoted = !quoted
+        }
+        else if !quoted && ch == sep {
+            return true
+        }
+        false
+    })
+}
+
+/// Parses a node of a forwarding header into an IP address.
+///
+/// The node may be quoted and may contain a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.strip_prefix('"').and_then(|node| {
+        node.strip_suffix('"')
+    }).unwrap_or(node);
+    if let Some(node) = node.strip_prefix('[') {
+        let (addr, _) = node.split_once(']')?;
+        return addr.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+    }
+    node.parse().ok().or_else(|| {
+        node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+    })
+}
+
+
 //------------ RequestPath ---------------------------------------------------
 
 #[derive(Debug)]
@@ -88,7 +636,7 @@ impl RequestPath {
         }
     }
 
-    pub fn iter(&self) -> PathIter {
+    pub fn iter(&self) -> PathIter<'_> {
         PathIter::new(self.as_str())
     }
 }
@@ -109,13 +657,8 @@ pub struct PathIter<'a> {
 }
 
 impl<'a> PathIter<'a> {
-    fn new(path: &'a str) -> Self {
-        let remaining = if path.starts_with('/') {
-            &path[1..]
-        }
-        else {
-            path
-        };
+    pub(crate) fn new(path: &'a str) -> Self {
+        let remaining = path.strip_prefix('/').unwrap_or(path);
         Self { full: path, remaining }
     }
 
@@ -160,7 +703,7 @@ impl<'a> Iterator for PathIter<'a> {
 
 //------------ RequestQuery -------------------------------------------------
 
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct RequestQuery {
     query: HashMap<String, QueryValue>,
 }
@@ -171,19 +714,24 @@ impl RequestQuery {
     }
 
     fn from_uri(uri: &Uri) -> Self {
+        Self::from_form(uri.query().unwrap_or("").as_bytes())
+    }
+
+    /// Parses URL-encoded form data.
+    fn from_form(data: &[u8]) -> Self {
         let mut res = Self::default();
-        form_urlencoded::parse(
-            uri.query().unwrap_or("").as_bytes()
-        ).into_owned().for_each(|(key, value)| { res.insert(key, value); });
+        form_urlencoded::parse(data).into_owned().for_each(|(key, value)| {
+            res.insert(key, value);
+        });
         res
     }
 
-    fn insert(&mut self, key: String, value: String) {
+    pub(crate) fn insert(&mut self, key: String, value: String) {
         use std::collections::hash_map::Entry::*;
 
         match self.query.entry(key) {
             Occupied(mut entry) => {
-                entry.get_mut().into_multi().push(value)
+                entry.get_mut().make_multi().push(value)
             }
             Vacant(entry) => {
                 entry.insert(QueryValue::Single(value));
@@ -208,6 +756,31 @@ impl RequestQuery {
             QueryValue::Multi(vec) => vec.first().map(String::as_str)
         }
     }
+
+    /// Checks that the parameters conform to the given spec.
+    ///
+    /// If they don’t, returns an error listing all problems. The error
+    /// can be converted into a Bad Request response.
+    pub fn check(&self, spec: &QuerySpec) -> Result<(), QueryError> {
+        spec.check(self.query.iter().map(|(key, value)| {
+            let values = match value {
+                QueryValue::Single(s) => slice::from_ref(s),
+                QueryValue::Multi(vec) => vec.as_slice(),
+            };
+            (key.as_str(), values)
+        }))
+    }
+
+    /// Extracts a typed value from the parameters.
+    ///
+    /// If any parameters are missing or invalid, returns an error listing
+    /// all of them. The error can be converted into a Bad Request
+    /// response.
+    pub fn extract<T: FromQuery>(&self) -> Result<T, QueryError> {
+        let mut parser = QueryParser::new(self);
+        let res = T::from_query(&mut parser);
+        parser.finish(res)
+    }
 }
 
 
@@ -220,7 +793,7 @@ pub enum QueryValue {
 }
 
 impl QueryValue {
-    fn into_multi(&mut self) -> &mut Vec<String> {
+    fn make_multi(&mut self) -> &mut Vec<String> {
         if let Self::Multi(ref mut vec) = self {
             return vec
         }
@@ -251,6 +824,209 @@ mod test {
     use std::str::FromStr;
     use super::*;
 
+    #[test]
+    fn client_addr() {
+        fn client(
+            trusted: &TrustedProxies,
+            peer: PeerAddr,
+            headers: &[(&'static str, &str)],
+        ) -> Option<IpAddr> {
+            let mut map = HeaderMap::new();
+            for (key, value) in headers {
+                map.append(*key, HeaderValue::from_str(value).unwrap());
+            }
+            trusted.client_addr(peer, &map).0
+        }
+
+        let mut trusted = TrustedProxies::default();
+        trusted.add("192.0.2.1".parse().unwrap());
+        trusted.add("2001:db8::1".parse().unwrap());
+        let proxy = PeerAddr::Tcp("192.0.2.1:4711".parse().unwrap());
+        let other = PeerAddr::Tcp("198.51.100.7:4711".parse().unwrap());
+
+        assert_eq!(
+            client(&trusted, other, &[("X-Forwarded-For", "203.0.113.9")]),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(
+            client(&trusted, proxy, &[]),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            client(&trusted, proxy, &[
+                ("X-Forwarded-For", "203.0.113.9, 198.51.100.8"),
+                ("X-Forwarded-For", "2001:db8::1"),
+            ]),
+            Some("198.51.100.8".parse().unwrap())
+        );
+        assert_eq!(
+            client(&trusted, proxy, &[
+                ("Forwarded", "for=203.0.113.9;proto=https"),
+                ("Forwarded", "for=\"[2001:db8::1]:4711\", by=x"),
+                ("X-Forwarded-For", "198.51.100.8"),
+            ]),
+            None
+        );
+        assert_eq!(
+            client(&trusted, proxy, &[
+                ("Forwarded",
+                 "For=\"203.0.113.9:80\", for=\"[2001:db8::1]:4711\""),
+            ]),
+            Some("203.0.113.9".parse().unwrap())
+        );
+        assert_eq!(
+            client(&trusted, proxy, &[("Forwarded", "for=unknown")]),
+            None
+        );
+        assert_eq!(
+            client(&trusted, PeerAddr::Unix, &[("Forwarded", "for=[::2]")]),
+            None
+        );
+        trusted.set_unix(true);
+        assert_eq!(
+            client(&trusted, PeerAddr::Unix, &[("Forwarded", "for=[::2]")]),
+            Some("::2".parse().unwrap())
+        );
+
+        // IPv4-mapped addresses are treated as their IPv4 address.
+        let mapped = PeerAddr::Tcp(
+            "[::ffff:192.0.2.1]:4711".parse().unwrap()
+        );
+        assert_eq!(
+            client(&trusted, mapped, &[
+                ("X-Forwarded-For", "::ffff:198.51.100.8")
+            ]),
+            Some("198.51.100.8".parse().unwrap())
+        );
+        let mapped = PeerAddr::Tcp(
+            "[::ffff:198.51.100.7]:4711".parse().unwrap()
+        );
+        assert_eq!(
+            client(&trusted, mapped, &[("X-Forwarded-For", "203.0.113.9")]),
+            Some("198.51.100.7".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn body() {
+        fn request(body: &'static str) -> Request {
+            hyper::Request::new(Body::from(body)).into()
+        }
+
+        assert_eq!(
+            request("foo").body_string(3).await.unwrap(),
+            "foo"
+        );
+        assert_eq!(
+            request("foo").body_bytes(2).await.unwrap_err()
+                .into_hyper().status(),
+            hyper::StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let mut req: Request = hyper::Request::builder()
+            .header("Content-Length", "3")
+            .body(Body::from("foo")).unwrap().into();
+        assert_eq!(
+            req.body_bytes(2).await.unwrap_err().into_hyper().status(),
+            hyper::StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let mut req: Request = hyper::Request::new(
+            Body::from(b"\xff".as_ref())
+        ).into();
+        assert_eq!(
+            req.body_string(10).await.unwrap_err().into_hyper().status(),
+            hyper::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn form() {
+        fn request(content_type: &str, body: &'static str) -> Request {
+            hyper::Request::builder()
+                .header("Content-Type", content_type)
+                .body(Body::from(body)).unwrap().into()
+        }
+
+        let form = request(
+            "application/x-www-form-urlencoded", "asn=64496&asn=64497&x=%2F"
+        ).form(100).await.unwrap();
+        assert_eq!(form.get("asn").unwrap(), ["64496", "64497"]);
+        assert_eq!(form.get_first("x"), Some("/"));
+        assert_eq!(
+            request("text/plain", "asn=1").form(100).await.unwrap_err()
+                .into_hyper().status(),
+            hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"asn\"\r\n\r\n\
+            64496\r\n--b--\r\n";
+        let sink = |_: &_| {
+            Err::<tokio::io::Sink, _>(Response::bad_request())
+        };
+        let form = request("multipart/form-data; boundary=\"b\"", body)
+            .multipart(MultipartLimits::new(), sink).await.unwrap();
+        assert_eq!(form.get_first("asn"), Some("64496"));
+        assert_eq!(
+            request("multipart/form-data", body)
+                .multipart(MultipartLimits::new(), sink).await.unwrap_err()
+                .into_hyper().status(),
+            hyper::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Outer {
+            inner: Inner,
+        }
+
+        #[derive(Debug, serde::Deserialize)]
+        struct Inner {
+            count: u32,
+        }
+
+        fn request(content_type: &str, body: &'static str) -> Request {
+            hyper::Request::builder()
+                .header("Content-Type", content_type)
+                .body(Body::from(body)).unwrap().into()
+        }
+
+        async fn error(mut req: Request) -> (hyper::StatusCode, String) {
+            let resp = req.json::<Outer>(100).await.unwrap_err();
+            let resp = resp.into_hyper();
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        assert_eq!(
+            request(
+                "application/json; charset=utf-8", "{\"inner\":{\"count\":4}}"
+            ).json::<Outer>(100).await.unwrap().inner.count,
+            4
+        );
+
+        let (status, _) = error(request("text/plain", "{}")).await;
+        assert_eq!(status, hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let (status, body) = error(request(
+            "application/json", "{\n\"inner\": {\"count\": true}}"
+        )).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["path"], "inner.count");
+        assert_eq!(body["line"], 2);
+
+        let (status, _) = error(request(
+            "application/json", "{\"inner\": {\"count\": 1}} x"
+        )).await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    }
+
     #[test]
     fn request_query() {
         let query = RequestQuery::from_uri(
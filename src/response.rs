@@ -1,11 +1,17 @@
 //! Building responses.
 
+use std::ops::Range;
 #[cfg(feature = "chrono")]
 use chrono::{DateTime, Utc};
-use hyper::{Body, StatusCode};
-use hyper::header::HeaderValue;
+use hyper::{Body, Method, StatusCode};
+use hyper::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, HeaderName, HeaderValue,
+};
 use hyper::http::response::Builder;
 #[cfg(feature = "chrono")]
+use crate::conditional::Preconditions;
+use crate::etag::ETag;
+#[cfg(feature = "chrono")]
 use crate::request::Request;
 
 
@@ -15,6 +21,11 @@ use crate::request::Request;
 pub struct Response(hyper::Response<Body>);
 
 impl Response {
+    /// Creates a response from a Hyper response.
+    pub fn from_hyper(response: hyper::Response<Body>) -> Self {
+        Response(response)
+    }
+
     pub fn ok(content_type: ContentType, body: impl Into<Body>) -> Self {
         ResponseBuilder::new().ok()
             .content_type(content_type)
@@ -37,12 +48,62 @@ impl Response {
 
     /// Returns a Not Modified response.
     #[cfg(feature = "chrono")]
-    pub fn not_modified(etag: &str, done: DateTime<Utc>) -> Self {
+    pub fn not_modified(etag: &ETag, done: DateTime<Utc>) -> Self {
         ResponseBuilder::new().not_modified()
             .etag(etag).last_modified(done)
             .empty()
     }
 
+    /// Returns a Payload Too Large response.
+    pub fn payload_too_large() -> Self {
+        ResponseBuilder::new().payload_too_large()
+            .content_type(ContentType::TEXT)
+            .body("Payload Too Large")
+    }
+
+    /// Returns an Internal Server Error response.
+    pub fn internal_server_error() -> Self {
+        ResponseBuilder::new().internal_server_error()
+            .content_type(ContentType::TEXT)
+            .body("Internal Server Error")
+    }
+
+    /// Returns a Precondition Failed response.
+    pub fn precondition_failed() -> Self {
+        ResponseBuilder::new().precondition_failed()
+            .content_type(ContentType::TEXT)
+            .body("Precondition Failed")
+    }
+
+    /// Returns a Range Not Satisfiable response.
+    ///
+    /// `len` is the length of the complete representation.
+    pub fn range_not_satisfiable(len: u64) -> Self {
+        ResponseBuilder::new().range_not_satisfiable()
+            .header(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))
+                    .expect("invalid Content-Range")
+            )
+            .content_type(ContentType::TEXT)
+            .body("Range Not Satisfiable")
+    }
+
+    /// Returns a Not Acceptable response.
+    ///
+    /// The body lists the content types that are available.
+    pub fn not_acceptable(available: &[ContentType]) -> Self {
+        let mut body = String::from("Not acceptable. Available types:\n");
+        for content_type in available {
+            body.push_str(content_type.as_str());
+            body.push('\n');
+        }
+        ResponseBuilder::new().not_acceptable()
+            .content_type(ContentType::TEXT)
+            .vary("Accept")
+            .body(body)
+    }
+
     /// Returns a Method Not Allowed response.
     pub fn method_not_allowed() -> Self {
         ResponseBuilder::new().method_not_allowed()
@@ -50,6 +111,17 @@ impl Response {
             .body("Method not allowed.")
     }
 
+    /// Returns a Method Not Allowed response for the given allowed methods.
+    ///
+    /// The methods are listed in the Allow header. If they include GET,
+    /// HEAD is listed as well.
+    pub fn method_not_allowed_with(allow: &[Method]) -> Self {
+        ResponseBuilder::new().method_not_allowed()
+            .allow(allow)
+            .content_type(ContentType::TEXT)
+            .body("Method not allowed.")
+    }
+
     /// Returns a Moved Permanently response pointing to the given location.
     pub fn moved_permanently(location: &str) -> Self {
         ResponseBuilder::new().moved_permanently()
@@ -63,43 +135,37 @@ impl Response {
     /// If either the etag or the completion time are referred to by the
     /// request, returns the reponse. If a new response needs to be generated,
     /// returns `None`.
+    ///
+    /// This only considers If-None-Match and If-Modified-Since. Use
+    /// [`Preconditions`][crate::conditional::Preconditions] to evaluate
+    /// all conditional headers.
     #[cfg(feature = "chrono")]
     pub fn maybe_not_modified(
         req: &Request,
-        etag: &str,
+        etag: &ETag,
         done: DateTime<Utc>,
     ) -> Option<Response> {
-        use crate::date::parse_http_date;
-
-        // First, check If-None-Match.
-        for value in req.headers().get_all("If-None-Match").iter() {
-            // Skip ill-formatted values. By being lazy here we may falsely
-            // return a full response, so this should be fine.
-            let value = match value.to_str() {
-                Ok(value) => value,
-                Err(_) => continue
-            };
-            let value = value.trim();
-            if value == "*" {
-                return Some(Self::not_modified(etag, done))
-            }
-            for tag in EtagsIter(value) {
-                if tag.trim() == etag {
-                    return Some(Self::not_modified(etag, done))
-                }
-            }
-        }
+        Preconditions::new()
+            .etag(etag.clone())
+            .last_modified(done)
+            .not_modified(req)
+    }
 
-        // Now, the If-Modified-Since header.
-        if let Some(value) = req.headers().get("If-Modified-Since") {
-            if let Some(date) = parse_http_date(value.to_str().ok()?) {
-                if date >= done {
-                    return Some(Self::not_modified(etag, done))
-                }
+    /// Converts the response into the response to a HEAD request.
+    ///
+    /// This drops the body. If the response doesn’t have a Content-Length
+    /// header yet and the size of the body is known, the header is added
+    /// with that size.
+    pub fn into_head(self) -> Self {
+        use hyper::body::HttpBody;
+
+        let (mut parts, body) = self.0.into_parts();
+        if !parts.headers.contains_key(CONTENT_LENGTH) {
+            if let Some(len) = body.size_hint().exact() {
+                parts.headers.insert(CONTENT_LENGTH, len.into());
             }
         }
-
-        None
+        Response(hyper::Response::from_parts(parts, Body::empty()))
     }
 
     /// Converts the response into a hyper response.
@@ -135,11 +201,21 @@ impl ResponseBuilder {
         self.status(StatusCode::OK)
     }
 
+    /// Creates a new builder for a 206 Partial Content response.
+    pub fn partial_content(self) -> Self {
+        self.status(StatusCode::PARTIAL_CONTENT)
+    }
+
     /// Creates a new builder for a Service Unavailable response.
     pub fn service_unavailable(self) -> Self {
         self.status(StatusCode::SERVICE_UNAVAILABLE)
     }
 
+    /// Creates a new builder for an Internal Server Error response.
+    pub fn internal_server_error(self) -> Self {
+        self.status(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
     /// Creates a new builder for a Bad Request response.
     pub fn bad_request(self) -> Self {
         self.status(StatusCode::BAD_REQUEST)
@@ -160,6 +236,31 @@ impl ResponseBuilder {
         self.status(StatusCode::METHOD_NOT_ALLOWED)
     }
 
+    /// Creates a new builder for a Not Acceptable response.
+    pub fn not_acceptable(self) -> Self {
+        self.status(StatusCode::NOT_ACCEPTABLE)
+    }
+
+    /// Creates a new builder for a Precondition Failed response.
+    pub fn precondition_failed(self) -> Self {
+        self.status(StatusCode::PRECONDITION_FAILED)
+    }
+
+    /// Creates a new builder for a Range Not Satisfiable response.
+    pub fn range_not_satisfiable(self) -> Self {
+        self.status(StatusCode::RANGE_NOT_SATISFIABLE)
+    }
+
+    /// Creates a new builder for a Payload Too Large response.
+    pub fn payload_too_large(self) -> Self {
+        self.status(StatusCode::PAYLOAD_TOO_LARGE)
+    }
+
+    /// Creates a new builder for an Unsupported Media Type response.
+    pub fn unsupported_media_type(self) -> Self {
+        self.status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    }
+
     /// Creates a new builder for a Moved Permanently response.
     pub fn moved_permanently(self) -> Self {
         self.status(StatusCode::MOVED_PERMANENTLY)
@@ -170,10 +271,30 @@ impl ResponseBuilder {
         Self::with(self.builder.header("Content-Type", content_type.0))
     }
 
+    /// Adds the Allow header listing the given methods.
+    ///
+    /// If the methods include GET but not HEAD, HEAD is added since HEAD
+    /// requests are handled automatically.
+    pub fn allow(self, methods: &[Method]) -> Self {
+        let mut value = String::new();
+        for method in methods {
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            value.push_str(method.as_str());
+        }
+        if methods.contains(&Method::GET)
+            && !methods.contains(&Method::HEAD)
+        {
+            value.push_str(", HEAD");
+        }
+        Self::with(self.builder.header("Allow", value))
+    }
+
     /// Adds the ETag header.
-    pub fn etag(self, etag: &str) -> Self {
+    pub fn etag(self, etag: &ETag) -> Self {
         ResponseBuilder {
-            builder: self.builder.header("ETag", etag)
+            builder: self.builder.header("ETag", etag.as_str())
         }
     }
 
@@ -188,6 +309,25 @@ impl ResponseBuilder {
         }
     }
 
+    /// Adds the Accept-Ranges header announcing support for byte ranges.
+    pub fn accept_ranges(self) -> Self {
+        self.header(ACCEPT_RANGES, HeaderValue::from_static("bytes"))
+    }
+
+    /// Adds the Content-Range header for a byte range.
+    ///
+    /// `range` is the range contained in the response and `len` the
+    /// length of the complete representation.
+    pub fn content_range(self, range: Range<u64>, len: u64) -> Self {
+        let value = format!(
+            "bytes {}-{}/{}", range.start, range.end.saturating_sub(1), len
+        );
+        self.header(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&value).expect("invalid Content-Range")
+        )
+    }
+
     /// Adds the Location header.
     pub fn location(self, location: &str) -> Self {
         ResponseBuilder {
@@ -198,6 +338,16 @@ impl ResponseBuilder {
         }
     }
 
+    /// Adds a Vary header with the given value.
+    ///
+    /// The header is appended, so the method can be called multiple times.
+    pub fn vary(mut self, value: &'static str) -> Self {
+        self.builder.headers_mut().unwrap().append(
+            "Vary", HeaderValue::from_static(value)
+        );
+        Self::with(self.builder)
+    }
+
     /// Adds a Set-Cookie header using a static str as the value.
     pub fn set_static_cookie(mut self, value: &'static str) -> Self {
         self.builder.headers_mut().unwrap().append(
@@ -206,6 +356,11 @@ impl ResponseBuilder {
         Self::with(self.builder)
     }
 
+    /// Adds a header.
+    pub(crate) fn header(self, name: HeaderName, value: HeaderValue) -> Self {
+        Self::with(self.builder.header(name, value))
+    }
+
     /// Finalizes the response by adding a body.
     pub fn body(self, body: impl Into<Body>) -> Response {
         Response(
@@ -221,6 +376,13 @@ impl ResponseBuilder {
 }
 
 
+impl Default for ResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
 //------------ ContentType ---------------------------------------------------
 
 #[derive(Clone, Debug)]
@@ -240,6 +402,9 @@ impl ContentType {
     pub const JSON: ContentType = ContentType::external(
         "application/json"
     );
+    pub const OCTET_STREAM: ContentType = ContentType::external(
+        "application/octet-stream"
+    );
     pub const SVG: ContentType = ContentType::external(
         "image/svg+xml"
     );
@@ -253,79 +418,10 @@ impl ContentType {
     pub const fn external(value: &'static str) -> Self {
         ContentType(HeaderValue::from_static(value))
     }
-}
-
-
-//------------ Parsing Etags -------------------------------------------------
-
-/// An iterator over the etags in an If-Not-Match header value.
-///
-/// This does not handle the "*" value.
-///
-/// One caveat: The iterator stops when it encounters bad formatting which
-/// makes this indistinguishable from reaching the end of a correctly
-/// formatted value. As a consequence, we will 304 a request that has the
-/// right tag followed by garbage.
-struct EtagsIter<'a>(&'a str);
-
-impl<'a> Iterator for EtagsIter<'a> {
-    type Item = &'a str;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Skip white space and check if we are done.
-        self.0 = self.0.trim_start();
-        if self.0.is_empty() {
-            return None
-        }
-
-        // We either have to have a lone DQUOTE or one prefixed by W/
-        let prefix_len = if self.0.starts_with('"') {
-            1
-        }
-        else if self.0.starts_with("W/\"") {
-            3
-        }
-        else {
-            return None
-        };
-
-        // Find the end of the tag which is after the next DQUOTE.
-        let end = match self.0[prefix_len..].find('"') {
-            Some(index) => index + prefix_len + 1,
-            None => return None
-        };
-
-        let res = &self.0[0..end];
 
-        // Move past the second DQUOTE and any space.
-        self.0 = self.0[end..].trim_start();
-
-        // If we have a comma, skip over that and any space.
-        if self.0.starts_with(',') {
-            self.0 = self.0[1..].trim_start();
-        }
-
-        Some(res)
-    }
-}
-
-
-//============ Tests =========================================================
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn etags_iter() {
-        assert_eq!(
-            EtagsIter("\"foo\", \"bar\", \"ba,zz\"").collect::<Vec<_>>(),
-            ["\"foo\"", "\"bar\"", "\"ba,zz\""]
-        );
-        assert_eq!(
-            EtagsIter("\"foo\", W/\"bar\" , \"ba,zz\", ").collect::<Vec<_>>(),
-            ["\"foo\"", "W/\"bar\"", "\"ba,zz\""]
-        );
+    /// Returns the content type as a string.
+    pub fn as_str(&self) -> &str {
+        // We only ever create values from strs, so this can’t fail.
+        self.0.to_str().unwrap_or("")
     }
 }
-
@@ -0,0 +1,460 @@
+//! Dispatching requests based on their path and method.
+
+use std::{fmt, slice};
+use std::borrow::Cow;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use futures_util::future::{BoxFuture, FutureExt, ready};
+use hyper::Method;
+use url::percent_encoding::percent_decode;
+use crate::request::{PathIter, Request, is_allowed};
+use crate::response::{ContentType, Response, ResponseBuilder};
+
+
+//------------ Router --------------------------------------------------------
+
+/// A router dispatching requests to handlers.
+///
+/// Routes are registered for a method and a path pattern. Patterns
+/// consist of segments separated by slashes. A segment can be literal
+/// text, a parameter such as `{asn}` matching exactly one segment, or,
+/// as the last segment only, a wildcard such as `*rest` matching the
+/// remainder of the path. For instance, the pattern
+/// `/api/v1/roas/{asn}/*rest` matches `/api/v1/roas/AS64496/foo/bar`
+/// with `asn` being `AS64496` and `rest` being `foo/bar`.
+///
+/// The path is split into segments before percent-decoding them, so an
+/// encoded slash in a segment doesn’t split it. Values of parameters and
+/// wildcards are percent-decoded.
+///
+/// Handlers have the same shape as the operation passed to
+/// [`serve`][crate::server::serve]. The parameters extracted from the
+/// path are available via [`Request::params`].
+///
+/// If no pattern matches a request, a 404 Not Found response is returned.
+/// If patterns match but none for the request’s method, a 405 Method Not
+/// Allowed response with an `Allow` header listing the methods of the
+/// matching patterns is returned. HEAD requests are dispatched to the
+/// first matching GET route if no matching route is registered for HEAD
+/// itself, no matter the order the routes were added in.
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+}
+
+/// A handler stored in a router.
+type Handler<T> = Box<
+    dyn Fn(Arc<T>, Request) -> BoxFuture<'static, Result<Response, Response>>
+    + Send + Sync
+>;
+
+struct Route<T> {
+    method: Method,
+    pattern: Pattern,
+    handler: Handler<T>,
+}
+
+impl<T> Router<T> {
+    /// Creates a new, empty router.
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    /// Adds a route for the given method and pattern.
+    ///
+    /// Routes are tried in the order they were added.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if the pattern is malformed.
+    pub fn route<F, Fut>(
+        mut self, method: Method, pattern: &str, op: F
+    ) -> Self
+    where
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(move |state, request| {
+                op(state, request).boxed()
+            }),
+        });
+        self
+    }
+
+    /// Adds a route for a GET request.
+    pub fn get<F, Fut>(self, pattern: &str, op: F) -> Self
+    where
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        self.route(Method::GET, pattern, op)
+    }
+
+    /// Adds a route for a POST request.
+    pub fn post<F, Fut>(self, pattern: &str, op: F) -> Self
+    where
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        self.route(Method::POST, pattern, op)
+    }
+
+    /// Adds a route for a PUT request.
+    pub fn put<F, Fut>(self, pattern: &str, op: F) -> Self
+    where
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        self.route(Method::PUT, pattern, op)
+    }
+
+    /// Adds a route for a DELETE request.
+    pub fn delete<F, Fut>(self, pattern: &str, op: F) -> Self
+    where
+        F: Fn(Arc<T>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send + 'static,
+    {
+        self.route(Method::DELETE, pattern, op)
+    }
+
+    /// Dispatches a request to the matching handler.
+    pub fn dispatch(
+        &self, state: Arc<T>, mut request: Request
+    ) -> BoxFuture<'static, Result<Response, Response>> {
+        let path = request.uri().path().to_owned();
+        if percent_decode(path.as_bytes()).decode_utf8().is_err() {
+            return ready(Err(Response::bad_request())).boxed()
+        }
+        let mut allow = Vec::new();
+        let mut fallback = None;
+        for route in &self.routes {
+            let params = match route.pattern.matches(PathIter::new(&path)) {
+                Some(params) => params,
+                None => continue,
+            };
+            if route.method == request.method() {
+                request.extensions_mut().insert(params);
+                return (route.handler)(state, request)
+            }
+            if is_allowed(request.method(), slice::from_ref(&route.method)) {
+                // A GET route for a HEAD request. Only use it if there is
+                // no HEAD route.
+                if fallback.is_none() {
+                    fallback = Some((route, params))
+                }
+            }
+            else if !allow.contains(&route.method) {
+                allow.push(route.method.clone())
+            }
+        }
+        if let Some((route, params)) = fallback {
+            request.extensions_mut().insert(params);
+            return (route.handler)(state, request)
+        }
+        ready(Err(
+            if allow.is_empty() {
+                Response::not_found()
+            }
+            else {
+                Response::method_not_allowed_with(&allow)
+            }
+        )).boxed()
+    }
+}
+
+impl<T: Send + Sync + 'static> Router<T> {
+    /// Converts the router into an operation for the server.
+    ///
+    /// The returned value can be passed to [`serve`][crate::server::serve]
+    /// or [`ServerBuilder`][crate::server::ServerBuilder].
+    pub fn into_op(
+        self
+    ) -> impl Fn(
+        Arc<T>, Request
+    ) -> BoxFuture<'static, Result<Response, Response>>
+        + Send + Sync + Clone + 'static
+    {
+        let router = Arc::new(self);
+        move |state, request| router.dispatch(state, request)
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+//------------ Pattern -------------------------------------------------------
+
+/// A parsed path pattern.
+#[derive(Clone, Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+
+    /// The name of the trailing wildcard if there is one.
+    rest: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut rest = None;
+        for item in PathIter::new(pattern) {
+            if rest.is_some() {
+                panic!("wildcard not last in pattern '{}'", pattern);
+            }
+            if let Some(name) = item.strip_prefix('*') {
+                rest = Some(name.into());
+            }
+            else if let Some(name) = item.strip_prefix('{') {
+                match name.strip_suffix('}') {
+                    Some(name) => segments.push(Segment::Param(name.into())),
+                    None => panic!("unclosed parameter in '{}'", pattern),
+                }
+            }
+            else {
+                segments.push(Segment::Literal(item.into()))
+            }
+        }
+        Pattern { segments, rest }
+    }
+
+    /// Matches the pattern against a path that is still percent-encoded.
+    ///
+    /// The path must decode into valid UTF-8.
+    fn matches(&self, mut path: PathIter) -> Option<PathParams> {
+        let mut params = PathParams::default();
+        for segment in &self.segments {
+            let item = decode(path.next()?);
+            match segment {
+                Segment::Literal(literal) => {
+                    if item != literal.as_str() {
+                        return None
+                    }
+                }
+                Segment::Param(name) => {
+                    params.params.push((name.clone(), item.into()))
+                }
+            }
+        }
+        match self.rest {
+            Some(ref name) => {
+                params.params.push(
+                    (name.clone(), decode(path.remaining()).into())
+                )
+            }
+            None => {
+                if path.next().is_some() {
+                    return None
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+
+/// Percent-decodes a part of a path.
+fn decode(s: &str) -> Cow<'_, str> {
+    percent_decode(s.as_bytes()).decode_utf8_lossy()
+}
+
+
+//------------ PathParams ----------------------------------------------------
+
+/// The parameters extracted from a request path by a router.
+#[derive(Clone, Debug, Default)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    /// Empty parameters for requests that didn’t go through a router.
+    pub(crate) const EMPTY: PathParams = PathParams { params: Vec::new() };
+
+    /// Returns the raw value of the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter().find_map(|(key, value)| {
+            (key == name).then_some(value.as_str())
+        })
+    }
+
+    /// Parses the parameter with the given name.
+    ///
+    /// If the parameter is missing or cannot be parsed, returns a Bad
+    /// Request response as the error.
+    pub fn parse<T>(&self, name: &str) -> Result<T, Response>
+    where T: FromStr, T::Err: fmt::Display {
+        let value = self.get(name).ok_or_else(|| {
+            ResponseBuilder::new().bad_request()
+                .content_type(ContentType::TEXT)
+                .body(format!("Missing path parameter '{}'.", name))
+        })?;
+        value.parse().map_err(|err| {
+            ResponseBuilder::new().bad_request()
+                .content_type(ContentType::TEXT)
+                .body(format!("Invalid path parameter '{}': {}", name, err))
+        })
+    }
+
+    /// Returns an iterator over the names and values of all parameters.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(key, value)| {
+            (key.as_str(), value.as_str())
+        })
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{Body, StatusCode};
+
+    fn request(method: Method, uri: &str) -> Request {
+        hyper::Request::builder().method(method).uri(uri)
+            .body(Body::empty()).unwrap().into()
+    }
+
+    async fn echo(
+        _: Arc<()>, request: Request
+    ) -> Result<Response, Response> {
+        let asn: u32 = request.params().parse("asn")?;
+        let rest = request.params().get("rest").unwrap_or("-");
+        Ok(Response::ok(ContentType::TEXT, format!("{} {}", asn, rest)))
+    }
+
+    async fn body(response: Response) -> String {
+        let body = response.into_hyper().into_body();
+        String::from_utf8(
+            hyper::body::to_bytes(body).await.unwrap().to_vec()
+        ).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatch() {
+        let router = Router::new()
+            .get("/roas/{asn}/*rest", echo)
+            .get("/roas/{asn}", echo)
+            .post("/roas/{asn}", echo);
+        let state = Arc::new(());
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/64496/foo/bar")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "64496 foo/bar");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::POST, "/roas/64496")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "64496 -");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/AS64496")
+        ).await.unwrap_err().into_hyper();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/aspa/64496")
+        ).await.unwrap_err().into_hyper();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::DELETE, "/roas/64496")
+        ).await.unwrap_err().into_hyper();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["Allow"], "GET, POST, HEAD");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::HEAD, "/roas/64496/foo")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "64496 foo");
+    }
+
+    #[tokio::test]
+    async fn dispatch_encoded() {
+        async fn asn(
+            _: Arc<()>, request: Request
+        ) -> Result<Response, Response> {
+            let asn = request.params().get("asn").unwrap().to_string();
+            let rest = request.params().get("rest").unwrap_or("-");
+            Ok(Response::ok(ContentType::TEXT, format!("{} {}", asn, rest)))
+        }
+
+        let router = Router::new()
+            .get("/roas/{asn}", asn)
+            .get("/roas/{asn}/*rest", asn)
+            .get("/a b/{asn}", asn);
+        let state = Arc::new(());
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/a%2Fb")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "a/b -");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/a/b%2Fc%20d")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "a b/c d");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/a%20b/x")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "x -");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/a%20b%2Fx")
+        ).await.unwrap_err().into_hyper();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/%FF")
+        ).await.unwrap_err().into_hyper();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn dispatch_head() {
+        async fn head(
+            _: Arc<()>, _: Request
+        ) -> Result<Response, Response> {
+            Ok(Response::ok(ContentType::TEXT, "head"))
+        }
+
+        let router = Router::new()
+            .get("/roas/{asn}", echo)
+            .route(Method::HEAD, "/roas/{asn}", head);
+        let state = Arc::new(());
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::HEAD, "/roas/64496")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "head");
+
+        let resp = router.dispatch(
+            state.clone(), request(Method::GET, "/roas/64496")
+        ).await.unwrap();
+        assert_eq!(body(resp).await, "64496 -");
+    }
+
+    #[test]
+    fn into_op() {
+        // Only check that the router fits into the server.
+        drop(crate::server::ServerBuilder::new().serve(
+            Arc::new(()), Router::new().get("/", echo).into_op()
+        ));
+    }
+}
@@ -1,41 +1,1380 @@
+//! Running an HTTP server.
+
+use std::{error, fmt, io};
 use std::convert::Infallible;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::{Pin, pin};
 use std::sync::Arc;
-use hyper::service::{make_service_fn, service_fn};
-use crate::request::Request;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use futures_util::future::{
+    BoxFuture, Either, FutureExt, Ready, ready, select, try_join_all
+};
+use hyper::{Body, Method};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::Sleep;
+use crate::request::{
+    ConnectionInfo, PeerAddr, Request, TrustedProxies
+};
 use crate::response::Response;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConfig, TlsIncoming};
+
+
+//------------ serve ---------------------------------------------------------
 
-pub async fn serve<T, F, Fut>(addr: SocketAddr, state: Arc<T>, op: F)
+/// Runs a server on the given address.
+///
+/// Each request is handed to `op` together with a clone of `state`. The
+/// function returns only if binding the address fails or accepting
+/// connections fails for good. Transient errors while accepting, such as
+/// running out of file descriptors, are retried.
+pub async fn serve<T, F, Fut>(
+    addr: SocketAddr, state: Arc<T>, op: F
+) -> Result<(), ServeError>
 where
     T: Send + Sync + 'static,
     F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
     Fut: Future<Output = Result<Response, Response>> + Send,
 {
-    let make_svc = make_service_fn(move |_conn| {
-        let state = state.clone();
-        let op = op.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |r| {
-                let state = state.clone();
-                let op = op.clone();
-                async move {
-                    Ok::<_, Infallible>(
-                        match op(state, r.into()).await {
-                            Ok(resp) => resp,
-                            Err(resp) => resp
-                        }.into_hyper()
+    ServerBuilder::new().bind(addr).serve(state, op).await
+}
+
+/// Runs a server on the given address until a shutdown signal arrives.
+///
+/// Once `signal` resolves, the server stops accepting new connections and
+/// waits for the requests currently in flight to finish. If
+/// `drain_timeout` is given, it waits at most that long before giving up
+/// on the remaining connections.
+///
+/// The returned value tells whether all connections closed in time. You
+/// can use [`terminate_signal`] as the signal to shut down on SIGTERM.
+///
+/// Use [`ServerBuilder`] if you need more control over the listening
+/// sockets.
+pub async fn serve_with_shutdown<T, F, Fut, S>(
+    addr: SocketAddr,
+    state: Arc<T>,
+    op: F,
+    signal: S,
+    drain_timeout: Option<Duration>,
+) -> Result<Shutdown, ServeError>
+where
+    T: Send + Sync + 'static,
+    F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
+    Fut: Future<Output = Result<Response, Response>> + Send,
+    S: Future<Output = ()>,
+{
+    ServerBuilder::new()
+        .bind(addr)
+        .drain_timeout(drain_timeout)
+        .serve_with_shutdown(state, op, signal).await
+}
+
+/// Runs the server until it has finished draining after a shutdown signal.
+async fn drain<E>(
+    server: impl Future<Output = Result<(), E>>,
+    signal: impl Future<Output = ()>,
+    drain_timeout: Option<Duration>,
+) -> Result<Shutdown, E> {
+    let mut server = pin!(server);
+    if let Either::Left((res, _)) = select(&mut server, pin!(signal)).await {
+        return res.map(|_| Shutdown::Clean)
+    }
+    match drain_timeout {
+        Some(drain_timeout) => {
+            match tokio::time::timeout(drain_timeout, server).await {
+                Ok(res) => res.map(|_| Shutdown::Clean),
+                Err(_) => Ok(Shutdown::TimedOut),
+            }
+        }
+        None => server.await.map(|_| Shutdown::Clean)
+    }
+}
+
+
+//------------ ServerBuilder -------------------------------------------------
+
+/// A builder for a server listening on any number of sockets.
+///
+/// All sockets are served by the same operation and share the same state.
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    /// The sockets to listen on.
+    sockets: Vec<Socket>,
+
+    /// The TCP keepalive interval for accepted connections.
+    keepalive: Option<Duration>,
+
+    /// Whether to set `TCP_NODELAY` on accepted connections.
+    nodelay: bool,
+
+    /// The file mode for Unix sockets bound by the server.
+    #[cfg(unix)]
+    unix_mode: Option<u32>,
+
+    /// The maximum number of concurrent TLS handshakes per listener.
+    #[cfg(feature = "tls")]
+    tls_handshake_limit: Option<usize>,
+
+    /// The reverse proxies whose forwarding headers we believe.
+    trusted_proxies: TrustedProxies,
+
+    /// Whether to only accept HTTP/1.
+    http1_only: bool,
+
+    /// Whether to only accept HTTP/2.
+    http2_only: bool,
+
+    /// How long to wait for connections to close during shutdown.
+    drain_timeout: Option<Duration>,
+}
+
+impl ServerBuilder {
+    /// Creates a new builder without any sockets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an address to listen on.
+    ///
+    /// The address will be bound when the server is started. IPv6
+    /// addresses are bound v6-only, so the IPv4 and IPv6 wildcard
+    /// addresses have to be added separately to listen on both.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.sockets.push(Socket::TcpAddr(addr));
+        self
+    }
+
+    /// Adds a listener that has already been bound.
+    ///
+    /// This can be used to take over sockets passed in by a service
+    /// manager such as systemd.
+    pub fn listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.sockets.push(Socket::TcpListener(listener));
+        self
+    }
+
+    /// Adds an address to listen on for HTTPS connections.
+    ///
+    /// The address will be bound when the server is started. Accepted
+    /// connections will use the given TLS configuration and negotiate the
+    /// HTTP version via ALPN.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls(mut self, addr: SocketAddr, tls: TlsConfig) -> Self {
+        self.sockets.push(Socket::TlsAddr(addr, tls));
+        self
+    }
+
+    /// Adds a listener that has already been bound for HTTPS connections.
+    #[cfg(feature = "tls")]
+    pub fn tls_listener(
+        mut self, listener: std::net::TcpListener, tls: TlsConfig
+    ) -> Self {
+        self.sockets.push(Socket::TlsListener(listener, tls));
+        self
+    }
+
+    /// Adds the path of a Unix domain socket to listen on.
+    ///
+    /// The socket will be bound when the server is started. If a stale
+    /// socket is left over at the path from an earlier run, it is removed
+    /// first. If the path exists but is not a socket or another process is
+    /// still listening on it, starting the server fails. The socket file is
+    /// removed again when the server stops.
+    #[cfg(unix)]
+    pub fn bind_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.sockets.push(Socket::UnixPath(path.into()));
+        self
+    }
+
+    /// Adds a Unix domain socket listener that has already been bound.
+    #[cfg(unix)]
+    pub fn unix_listener(
+        mut self, listener: std::os::unix::net::UnixListener
+    ) -> Self {
+        self.sockets.push(Socket::UnixListener(listener));
+        self
+    }
+
+    /// Sets the file mode of Unix domain sockets bound by the server.
+    ///
+    /// If `None`, which is the default, the mode is determined by the
+    /// process’ umask. Otherwise the socket only becomes reachable once
+    /// the mode has been applied. This needs write access to the
+    /// socket’s directory for a temporary directory next to it.
+    #[cfg(unix)]
+    pub fn unix_mode(mut self, mode: Option<u32>) -> Self {
+        self.unix_mode = mode;
+        self
+    }
+
+    /// Sets the maximum number of concurrent TLS handshakes per listener.
+    ///
+    /// While this many handshakes are in progress, no new connections are
+    /// accepted on the listener. If `None`, which is the default, the
+    /// limit is 1024.
+    #[cfg(feature = "tls")]
+    pub fn tls_handshake_limit(mut self, limit: Option<usize>) -> Self {
+        self.tls_handshake_limit = limit;
+        self
+    }
+
+    /// Sets the TCP keepalive interval for accepted connections.
+    ///
+    /// If `None`, which is the default, keepalive is disabled.
+    pub fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Sets whether `TCP_NODELAY` is set on accepted connections.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Adds the address of a trusted reverse proxy.
+    ///
+    /// For requests received from a trusted proxy, the client address
+    /// provided via [`Request::client_addr`] is taken from the `Forwarded`
+    /// or `X-Forwarded-For` header.
+    pub fn trusted_proxy(mut self, addr: IpAddr) -> Self {
+        self.trusted_proxies.add(addr);
+        self
+    }
+
+    /// Sets whether peers connecting via Unix sockets are trusted proxies.
+    #[cfg(unix)]
+    pub fn trust_unix_proxies(mut self, trust: bool) -> Self {
+        self.trusted_proxies.set_unix(trust);
+        self
+    }
+
+    /// Only accept HTTP/1 connections.
+    pub fn http1_only(mut self) -> Self {
+        self.http1_only = true;
+        self.http2_only = false;
+        self
+    }
+
+    /// Only accept HTTP/2 connections.
+    pub fn http2_only(mut self) -> Self {
+        self.http1_only = false;
+        self.http2_only = true;
+        self
+    }
+
+    /// Sets the time to wait for open connections during shutdown.
+    ///
+    /// If `None`, which is the default, the server waits for all
+    /// connections to close no matter how long it takes.
+    pub fn drain_timeout(mut self, drain_timeout: Option<Duration>) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Runs the server.
+    ///
+    /// The function returns only if preparing the sockets fails or
+    /// accepting connections on one of them fails for good. In the latter
+    /// case, all other sockets are closed, too. Transient errors while
+    /// accepting are retried.
+    pub async fn serve<T, F, Fut>(
+        self, state: Arc<T>, op: F
+    ) -> Result<(), ServeError>
+    where
+        T: Send + Sync + 'static,
+        F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send,
+    {
+        self.serve_with_shutdown(
+            state, op, std::future::pending()
+        ).await.map(|_| ())
+    }
+
+    /// Runs the server until a shutdown signal arrives.
+    ///
+    /// See [`serve_with_shutdown`] for details.
+    pub async fn serve_with_shutdown<T, F, Fut, S>(
+        self,
+        state: Arc<T>,
+        op: F,
+        signal: S,
+    ) -> Result<Shutdown, ServeError>
+    where
+        T: Send + Sync + 'static,
+        F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send,
+        S: Future<Output = ()>,
+    {
+        let listeners = self.listeners()?;
+        if listeners.is_empty() {
+            return Err(ServeError::NoListeners)
+        }
+
+        let (tx, rx) = watch::channel(());
+        let make_svc = MakeService::new(
+            state, op, self.trusted_proxies.clone()
+        );
+        let servers = try_join_all(listeners.into_iter().map(|listener| {
+            let mut rx = rx.clone();
+            let shutdown = async move {
+                let _ = rx.changed().await;
+            };
+            match listener {
+                Listener::Tcp(incoming) => {
+                    let addr = incoming.local_addr();
+                    self.server(
+                        incoming, make_svc.clone(), shutdown,
+                        move |err| ServeError::Accept { addr, err }
                     )
                 }
-            }))
+                #[cfg(feature = "tls")]
+                Listener::Tls(incoming) => {
+                    let addr = incoming.local_addr();
+                    self.server(
+                        incoming, make_svc.clone(), shutdown,
+                        move |err| ServeError::Accept { addr, err }
+                    )
+                }
+                #[cfg(unix)]
+                Listener::Unix(incoming) => {
+                    let path = incoming.path();
+                    self.server(
+                        incoming, make_svc.clone(), shutdown,
+                        move |err| ServeError::AcceptUnix { path, err }
+                    )
+                }
+            }
+        }));
+        drain(
+            servers.map(|res| res.map(|_| ())),
+            async move {
+                signal.await;
+                let _ = tx.send(());
+            },
+            self.drain_timeout,
+        ).await
+    }
+
+    /// Creates the hyper server for a single listener.
+    ///
+    /// The server only fails if accepting fails permanently. The error is
+    /// turned into a serve error via `map_err`.
+    fn server<I, T, F, Fut>(
+        &self,
+        incoming: I,
+        make_svc: MakeService<T, F>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+        map_err: impl FnOnce(hyper::Error) -> ServeError + Send + 'static,
+    ) -> BoxFuture<'static, Result<(), ServeError>>
+    where
+        I: Accept<Error = io::Error> + Unpin + Send + 'static,
+        I::Conn: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+        T: Send + Sync + 'static,
+        F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
+        Fut: Future<Output = Result<Response, Response>> + Send,
+    {
+        hyper::Server::builder(RetryIncoming::new(incoming))
+            .http1_only(self.http1_only)
+            .http2_only(self.http2_only)
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown)
+            .map(|res| res.map_err(map_err))
+            .boxed()
+    }
+
+    /// Binds all addresses and prepares all listeners.
+    fn listeners(&self) -> Result<Vec<Listener>, ServeError> {
+        self.sockets.iter().map(|socket| {
+            match socket {
+                Socket::TcpAddr(addr) => {
+                    bind_tcp(*addr).and_then(|listener| {
+                        self.prepare_tcp(listener)
+                    }).map_err(|err| ServeError::Bind {
+                        addr: Some(*addr), err
+                    })
+                }
+                Socket::TcpListener(listener) => {
+                    listener.try_clone().and_then(|listener| {
+                        self.prepare_tcp(listener)
+                    }).map_err(|err| ServeError::Bind {
+                        addr: listener.local_addr().ok(), err
+                    })
+                }
+                #[cfg(feature = "tls")]
+                Socket::TlsAddr(addr, tls) => {
+                    bind_tcp(*addr).and_then(|listener| {
+                        self.prepare_tls(listener, tls)
+                    }).map_err(|err| ServeError::Bind {
+                        addr: Some(*addr), err
+                    })
+                }
+                #[cfg(feature = "tls")]
+                Socket::TlsListener(listener, tls) => {
+                    listener.try_clone().and_then(|listener| {
+                        self.prepare_tls(listener, tls)
+                    }).map_err(|err| ServeError::Bind {
+                        addr: listener.local_addr().ok(), err
+                    })
+                }
+                #[cfg(unix)]
+                Socket::UnixPath(path) => {
+                    UnixIncoming::bind(path, self.unix_mode).map(
+                        Listener::Unix
+                    ).map_err(|err| ServeError::BindUnix {
+                        path: Some(path.clone()), err
+                    })
+                }
+                #[cfg(unix)]
+                Socket::UnixListener(listener) => {
+                    listener.try_clone().and_then(|listener| {
+                        UnixIncoming::from_std(listener, None)
+                    }).map(Listener::Unix).map_err(|err| {
+                        ServeError::BindUnix {
+                            path: listener.local_addr().ok().and_then(|addr| {
+                                addr.as_pathname().map(Into::into)
+                            }),
+                            err
+                        }
+                    })
+                }
+            }
+        }).collect()
+    }
+
+    fn prepare_tcp(
+        &self, listener: std::net::TcpListener
+    ) -> Result<Listener, io::Error> {
+        self.addr_incoming(listener).map(Listener::Tcp)
+    }
+
+    #[cfg(feature = "tls")]
+    fn prepare_tls(
+        &self, listener: std::net::TcpListener, tls: &TlsConfig,
+    ) -> Result<Listener, io::Error> {
+        let alpn: &[&[u8]] = if self.http1_only {
+            &[b"http/1.1"]
+        }
+        else if self.http2_only {
+            &[b"h2"]
         }
-    });
+        else {
+            &[b"h2", b"http/1.1"]
+        };
+        Ok(Listener::Tls(TlsIncoming::new(
+            self.addr_incoming(listener)?, tls.acceptor(alpn),
+            self.tls_handshake_limit.unwrap_or(DEFAULT_TLS_HANDSHAKE_LIMIT),
+        )))
+    }
+
+    fn addr_incoming(
+        &self, listener: std::net::TcpListener
+    ) -> Result<AddrIncoming, io::Error> {
+        listener.set_nonblocking(true)?;
+        let mut incoming = AddrIncoming::from_listener(
+            TcpListener::from_std(listener)?
+        ).map_err(io::Error::other)?;
+        incoming.set_keepalive(self.keepalive);
+        incoming.set_nodelay(self.nodelay);
+        // We do our own retrying in `RetryIncoming`.
+        incoming.set_sleep_on_errors(false);
+        Ok(incoming)
+    }
+}
+
+
+/// The default maximum number of concurrent TLS handshakes.
+#[cfg(feature = "tls")]
+const DEFAULT_TLS_HANDSHAKE_LIMIT: usize = 1024;
+
 
-    let server = hyper::Server::bind(&addr).serve(make_svc);
+//------------ Socket --------------------------------------------------------
 
-    // Run this server for ... ever!
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+/// A socket to listen on as configured in the builder.
+#[derive(Debug)]
+enum Socket {
+    TcpAddr(SocketAddr),
+    TcpListener(std::net::TcpListener),
+    #[cfg(feature = "tls")]
+    TlsAddr(SocketAddr, TlsConfig),
+    #[cfg(feature = "tls")]
+    TlsListener(std::net::TcpListener, TlsConfig),
+    #[cfg(unix)]
+    UnixPath(PathBuf),
+    #[cfg(unix)]
+    UnixListener(std::os::unix::net::UnixListener),
+}
+
+
+/// Binds a TCP listener to the given address.
+///
+/// IPv6 sockets are bound as v6-only so that the IPv4 and IPv6 wildcard
+/// addresses can be bound on the same port at the same time.
+fn bind_tcp(addr: SocketAddr) -> Result<std::net::TcpListener, io::Error> {
+    use socket2::{Domain, Protocol, Type};
+
+    let socket = socket2::Socket::new(
+        Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
     }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+
+//------------ Listener ------------------------------------------------------
+
+/// A socket that is ready to accept connections.
+enum Listener {
+    Tcp(AddrIncoming),
+    #[cfg(feature = "tls")]
+    Tls(TlsIncoming),
+    #[cfg(unix)]
+    Unix(UnixIncoming),
+}
+
+
+//------------ UnixIncoming --------------------------------------------------
+
+/// A listening Unix domain socket.
+#[cfg(unix)]
+struct UnixIncoming {
+    /// The listener.
+    listener: tokio::net::UnixListener,
+
+    /// The path of the socket file if we need to remove it when done.
+    path: Option<PathBuf>,
 }
 
+#[cfg(unix)]
+impl UnixIncoming {
+    /// Binds a new socket at the given path.
+    ///
+    /// Removes a stale socket and sets the file mode if requested.
+    fn bind(path: &Path, mode: Option<u32>) -> Result<Self, io::Error> {
+        use std::fs;
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        match fs::symlink_metadata(path) {
+            Ok(meta) => {
+                if !meta.file_type().is_socket() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "path exists and is not a socket"
+                    ))
+                }
+
+                // If we can connect, someone is still listening.
+                match UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            "socket is in use"
+                        ))
+                    }
+                    Err(err)
+                        if err.kind() == io::ErrorKind::ConnectionRefused
+                    => {
+                        fs::remove_file(path)?
+                    }
+                    Err(err) => return Err(err)
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => { }
+            Err(err) => return Err(err)
+        }
+
+        let listener = match mode {
+            Some(mode) => Self::bind_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        Self::from_std(listener, Some(path.into()))
+    }
+
+    /// Binds a new socket at the given path with the given file mode.
+    ///
+    /// So that nobody can connect before the mode is set, the socket is
+    /// bound in a new directory only accessible by us and then moved
+    /// into place.
+    fn bind_with_mode(
+        path: &Path, mode: u32
+    ) -> Result<std::os::unix::net::UnixListener, io::Error> {
+        use std::fs;
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid socket path")
+        })?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}", std::process::id()));
+        let tmp_dir = path.with_file_name(tmp_name);
+        fs::DirBuilder::new().mode(0o700).create(&tmp_dir)?;
+
+        let tmp_path = tmp_dir.join("s");
+        let res = std::os::unix::net::UnixListener::bind(
+            &tmp_path
+        ).and_then(|listener| {
+            fs::set_permissions(
+                &tmp_path, fs::Permissions::from_mode(mode)
+            )?;
+            fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&tmp_path);
+        let _ = fs::remove_dir(&tmp_dir);
+        res
+    }
+
+    fn from_std(
+        listener: std::os::unix::net::UnixListener,
+        path: Option<PathBuf>,
+    ) -> Result<Self, io::Error> {
+        listener.set_nonblocking(true)?;
+        Ok(UnixIncoming {
+            listener: tokio::net::UnixListener::from_std(listener)?,
+            path,
+        })
+    }
+
+    /// Returns the path of the socket if it has one.
+    fn path(&self) -> Option<PathBuf> {
+        self.listener.local_addr().ok().and_then(|addr| {
+            addr.as_pathname().map(Into::into)
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixIncoming {
+    type Conn = tokio::net::UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.listener.poll_accept(cx).map(|res| {
+            Some(res.map(|(stream, _)| stream))
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixIncoming {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+//------------ RetryIncoming -------------------------------------------------
+
+/// A listener that retries accepting after transient errors.
+///
+/// Errors that only concern the accepted connection are ignored. After
+/// errors that are likely to go away by themselves, such as running out
+/// of file descriptors, we wait a second before trying again. All other
+/// errors are returned and end the server.
+struct RetryIncoming<I> {
+    /// The wrapped listener.
+    incoming: I,
+
+    /// The delay before accepting again after an error.
+    retry: Option<Pin<Box<Sleep>>>,
+}
+
+impl<I> RetryIncoming<I> {
+    fn new(incoming: I) -> Self {
+        RetryIncoming { incoming, retry: None }
+    }
+}
+
+impl<I> Accept for RetryIncoming<I>
+where I: Accept<Error = io::Error> + Unpin {
+    type Conn = I::Conn;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            if let Some(retry) = this.retry.as_mut() {
+                ready!(retry.as_mut().poll(cx));
+                this.retry = None;
+            }
+            match ready!(Pin::new(&mut this.incoming).poll_accept(cx)) {
+                Some(Err(err)) if is_connection_error(&err) => { }
+                Some(Err(err)) if is_transient_error(&err) => {
+                    this.retry = Some(Box::pin(
+                        tokio::time::sleep(ACCEPT_RETRY)
+                    ));
+                }
+                res => return Poll::Ready(res)
+            }
+        }
+    }
+}
+
+/// How long to wait before accepting again after a transient failure.
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// Returns whether an accept error only concerns the accepted connection.
+fn is_connection_error(err: &io::Error) -> bool {
+    if matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+    ) {
+        return true
+    }
+
+    // Linux reports network errors pending on the new connection via
+    // accept.
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        return matches!(
+            code,
+            libc::EPROTO | libc::ENETDOWN | libc::ENETUNREACH
+            | libc::EHOSTUNREACH | libc::ETIMEDOUT
+        )
+    }
+    false
+}
+
+/// Returns whether an accept error is likely to go away by itself.
+///
+/// Outside of Unix systems, we can’t tell and consider all errors
+/// transient.
+fn is_transient_error(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        err.kind() == io::ErrorKind::OutOfMemory
+        || matches!(
+            err.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = err;
+        true
+    }
+}
+
+
+//------------ terminate_signal ----------------------------------------------
+
+/// Waits until the process is asked to terminate.
+///
+/// On Unix systems, this resolves when either SIGTERM or SIGINT is
+/// received. Elsewhere, only Ctrl-C is considered.
+pub async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            select(pin!(term.recv()), pin!(tokio::signal::ctrl_c())).await;
+            return
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        // We can’t listen for the signal, so we’ll never see it.
+        std::future::pending::<()>().await
+    }
+}
+
+
+//------------ Shutdown ------------------------------------------------------
+
+/// The outcome of a graceful shutdown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Shutdown {
+    /// All connections were closed before the drain timeout expired.
+    Clean,
+
+    /// The drain timeout expired while connections were still open.
+    TimedOut,
+}
+
+impl Shutdown {
+    /// Returns whether all connections were closed cleanly.
+    pub fn is_clean(self) -> bool {
+        matches!(self, Shutdown::Clean)
+    }
+}
+
+
+//------------ ServeError ----------------------------------------------------
+
+/// An error happened while running a server.
+#[derive(Debug)]
+pub enum ServeError {
+    /// The server was started without any sockets to listen on.
+    NoListeners,
+
+    /// Binding or preparing a listening socket failed.
+    Bind {
+        /// The address of the socket if it is known.
+        addr: Option<SocketAddr>,
+
+        /// The underlying error.
+        err: io::Error,
+    },
+
+    /// Binding or preparing a listening Unix domain socket failed.
+    #[cfg(unix)]
+    BindUnix {
+        /// The path of the socket if it is known.
+        path: Option<PathBuf>,
+
+        /// The underlying error.
+        err: io::Error,
+    },
+
+    /// Accepting connections on a socket failed while running.
+    Accept {
+        /// The address of the socket.
+        addr: SocketAddr,
+
+        /// The underlying error.
+        err: hyper::Error,
+    },
+
+    /// Accepting connections on a Unix domain socket failed while running.
+    #[cfg(unix)]
+    AcceptUnix {
+        /// The path of the socket if it is known.
+        path: Option<PathBuf>,
+
+        /// The underlying error.
+        err: hyper::Error,
+    },
+}
+
+impl fmt::Display for ServeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServeError::NoListeners => {
+                f.write_str("no sockets to listen on")
+            }
+            ServeError::Bind { addr: Some(addr), err } => {
+                write!(f, "failed to listen on {}: {}", addr, err)
+            }
+            ServeError::Bind { addr: None, err } => {
+                write!(f, "failed to listen: {}", err)
+            }
+            #[cfg(unix)]
+            ServeError::BindUnix { path: Some(path), err } => {
+                write!(f, "failed to listen on {}: {}", path.display(), err)
+            }
+            #[cfg(unix)]
+            ServeError::BindUnix { path: None, err } => {
+                write!(f, "failed to listen: {}", err)
+            }
+            ServeError::Accept { addr, err } => {
+                write!(f, "failed to accept on {}: {}", addr, err)
+            }
+            #[cfg(unix)]
+            ServeError::AcceptUnix { path: Some(path), err } => {
+                write!(f, "failed to accept on {}: {}", path.display(), err)
+            }
+            #[cfg(unix)]
+            ServeError::AcceptUnix { path: None, err } => {
+                write!(f, "failed to accept: {}", err)
+            }
+        }
+    }
+}
+
+impl error::Error for ServeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServeError::NoListeners => None,
+            ServeError::Bind { err, .. } => Some(err),
+            #[cfg(unix)]
+            ServeError::BindUnix { err, .. } => Some(err),
+            ServeError::Accept { err, .. } => Some(err),
+            #[cfg(unix)]
+            ServeError::AcceptUnix { err, .. } => Some(err),
+        }
+    }
+}
+
+
+//------------ Connection ----------------------------------------------------
+
+/// A connection accepted by one of our listeners.
+trait Connection {
+    /// Returns information about the connection.
+    fn info(&self) -> ConnectionInfo;
+}
+
+impl Connection for AddrStream {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(
+            PeerAddr::Tcp(self.remote_addr()), Some(self.local_addr()), false
+        )
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Connection for tokio_rustls::server::TlsStream<AddrStream> {
+    fn info(&self) -> ConnectionInfo {
+        self.get_ref().0.info().with_tls()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for tokio::net::UnixStream {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(PeerAddr::Unix, None, false)
+    }
+}
+
+
+//------------ MakeService ---------------------------------------------------
+
+/// The hyper service creating a new service for each connection.
+struct MakeService<T, F> {
+    state: Arc<T>,
+    op: F,
+    trusted: Arc<TrustedProxies>,
+}
+
+impl<T, F> MakeService<T, F> {
+    fn new(state: Arc<T>, op: F, trusted: TrustedProxies) -> Self {
+        MakeService { state, op, trusted: Arc::new(trusted) }
+    }
+}
+
+impl<T, F: Clone> Clone for MakeService<T, F> {
+    fn clone(&self) -> Self {
+        MakeService {
+            state: self.state.clone(),
+            op: self.op.clone(),
+            trusted: self.trusted.clone(),
+        }
+    }
+}
+
+impl<'a, Conn, T, F> hyper::service::Service<&'a Conn> for MakeService<T, F>
+where Conn: Connection, F: Clone {
+    type Response = Service<T, F>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self, _cx: &mut Context
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, conn: &'a Conn) -> Self::Future {
+        ready(Ok(Service {
+            state: self.state.clone(),
+            op: self.op.clone(),
+            trusted: self.trusted.clone(),
+            conn: conn.info(),
+        }))
+    }
+}
+
+
+//------------ Service -------------------------------------------------------
+
+/// The hyper service handing requests of a connection to the operation.
+struct Service<T, F> {
+    state: Arc<T>,
+    op: F,
+    trusted: Arc<TrustedProxies>,
+    conn: ConnectionInfo,
+}
+
+impl<T, F, Fut> hyper::service::Service<hyper::Request<Body>>
+for Service<T, F>
+where
+    T: Send + Sync + 'static,
+    F: (Fn(Arc<T>, Request) -> Fut) + Send + Sync + Clone + 'static,
+    Fut: Future<Output = Result<Response, Response>> + Send,
+{
+    type Response = hyper::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<
+        'static, Result<hyper::Response<Body>, Infallible>
+    >;
+
+    fn poll_ready(
+        &mut self, _cx: &mut Context
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: hyper::Request<Body>) -> Self::Future {
+        let convert = if request.method() == Method::HEAD {
+            into_hyper_head
+        }
+        else {
+            into_hyper
+        };
+        let client = self.trusted.client_addr(
+            self.conn.peer(), request.headers()
+        );
+        request.extensions_mut().insert(self.conn);
+        request.extensions_mut().insert(client);
+
+        // The operation is called inside the async block so that its
+        // future doesn’t need to be `'static`.
+        let op = self.op.clone();
+        let state = self.state.clone();
+        async move {
+            convert(op(state, request.into()).await)
+        }.boxed()
+    }
+}
+
+fn into_hyper(
+    res: Result<Response, Response>
+) -> Result<hyper::Response<Body>, Infallible> {
+    Ok(match res {
+        Ok(resp) => resp,
+        Err(resp) => resp,
+    }.into_hyper())
+}
+
+fn into_hyper_head(
+    res: Result<Response, Response>
+) -> Result<hyper::Response<Body>, Infallible> {
+    Ok(match res {
+        Ok(resp) => resp,
+        Err(resp) => resp,
+    }.into_head().into_hyper())
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_without_connections() {
+        let res = serve_with_shutdown(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Arc::new(()),
+            |_, _| async { Ok(Response::not_found()) },
+            async { },
+            Some(Duration::from_secs(1)),
+        ).await;
+        assert_eq!(res.unwrap(), Shutdown::Clean);
+    }
+
+    /// Shuts down a server while a request is in flight.
+    ///
+    /// The request takes 200 ms. Returns the shutdown outcome and, if
+    /// `read_response` is true, the response received.
+    async fn shutdown_in_flight(
+        read_response: bool, drain_timeout: Duration
+    ) -> (Shutdown, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::sync::Notify;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let started = Arc::new(Notify::new());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .listener(listener)
+                .drain_timeout(Some(drain_timeout))
+                .serve_with_shutdown(
+                    started.clone(),
+                    |started: Arc<Notify>, _| async move {
+                        started.notify_one();
+                        tokio::time::sleep(
+                            Duration::from_millis(200)
+                        ).await;
+                        Ok(Response::not_found())
+                    },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        started.notified().await;
+        tx.send(()).unwrap();
+        let mut resp = String::new();
+        if read_response {
+            sock.read_to_string(&mut resp).await.unwrap();
+        }
+        (server.await.unwrap().unwrap(), resp)
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_requests() {
+        let (res, resp) = shutdown_in_flight(
+            true, Duration::from_secs(10)
+        ).await;
+        assert_eq!(res, Shutdown::Clean);
+        assert!(resp.starts_with("HTTP/1.0 404"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_times_out() {
+        let (res, _) = shutdown_in_flight(
+            false, Duration::from_millis(10)
+        ).await;
+        assert_eq!(res, Shutdown::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn serve_errors() {
+        assert!(matches!(
+            ServerBuilder::new().serve(
+                Arc::new(()),
+                |_, _| async { Ok(Response::not_found()) },
+            ).await,
+            Err(ServeError::NoListeners)
+        ));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(matches!(
+            serve(
+                addr, Arc::new(()),
+                |_, _| async { Ok(Response::not_found()) },
+            ).await,
+            Err(ServeError::Bind { addr: Some(err_addr), .. })
+                if err_addr == addr
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_accept_error() {
+        // Accepting on a socket that isn’t listening fails for good.
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV4, socket2::Type::STREAM, None
+        ).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            ServerBuilder::new().listener(socket.into()).serve(
+                Arc::new(()),
+                |_, _| async { Ok(Response::not_found()) },
+            )
+        ).await.unwrap();
+        assert!(matches!(
+            res,
+            Err(ServeError::Accept { addr: err_addr, .. })
+                if err_addr == addr
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn retry_incoming() {
+        use std::collections::VecDeque;
+
+        struct Incoming(VecDeque<Result<(), io::Error>>);
+
+        impl Accept for Incoming {
+            type Conn = ();
+            type Error = io::Error;
+
+            fn poll_accept(
+                mut self: Pin<&mut Self>, _cx: &mut Context
+            ) -> Poll<Option<Result<(), io::Error>>> {
+                Poll::Ready(self.0.pop_front())
+            }
+        }
+
+        async fn accept(
+            incoming: &mut RetryIncoming<Incoming>
+        ) -> Option<Result<(), io::Error>> {
+            futures_util::future::poll_fn(|cx| {
+                Pin::new(&mut *incoming).poll_accept(cx)
+            }).await
+        }
+
+        let mut incoming = RetryIncoming::new(Incoming(VecDeque::from([
+            Err(io::ErrorKind::ConnectionReset.into()),
+            Err(io::Error::from_raw_os_error(libc::EMFILE)),
+            Ok(()),
+            Err(io::Error::from_raw_os_error(libc::EBADF)),
+        ])));
+        let start = tokio::time::Instant::now();
+        assert!(matches!(accept(&mut incoming).await, Some(Ok(()))));
+        assert!(start.elapsed() >= ACCEPT_RETRY);
+        assert!(matches!(
+            accept(&mut incoming).await,
+            Some(Err(err)) if err.raw_os_error() == Some(libc::EBADF)
+        ));
+        assert!(accept(&mut incoming).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bind_dual_stack() {
+        let port = std::net::TcpListener::bind(
+            "127.0.0.1:0"
+        ).unwrap().local_addr().unwrap().port();
+        let listeners = ServerBuilder::new()
+            .bind(SocketAddr::from(([0, 0, 0, 0], port)))
+            .bind(SocketAddr::from(([0u16; 8], port)))
+            .listeners()
+            .unwrap();
+        assert_eq!(listeners.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn serve_listener() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .listener(listener)
+                .tcp_nodelay(true)
+                .http1_only()
+                .serve_with_shutdown(
+                    Arc::new(()),
+                    move |_, req: Request| async move {
+                        if req.local_addr() == Some(addr)
+                            && req.client_addr() == Some(addr.ip())
+                            && !req.is_tls()
+                        {
+                            Ok(Response::not_found())
+                        }
+                        else {
+                            Err(Response::bad_request())
+                        }
+                    },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        sock.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 404"));
+
+        tx.send(()).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), Shutdown::Clean);
+    }
+
+    #[tokio::test]
+    async fn serve_head() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use crate::response::ContentType;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .listener(listener)
+                .serve_with_shutdown(
+                    Arc::new(()),
+                    |_, req: Request| async move {
+                        req.require_get()?;
+                        Ok(Response::ok(ContentType::TEXT, "hello"))
+                    },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        let mut sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"HEAD / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        sock.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 200"));
+        assert!(resp.contains("content-length: 5\r\n"));
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        tx.send(()).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), Shutdown::Clean);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(
+            format!("httools-test-{}.sock", std::process::id())
+        );
+
+        // Leave a stale socket behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .bind_unix(&path)
+                .unix_mode(Some(0o600))
+                .serve_with_shutdown(
+                    Arc::new(()),
+                    |_, req: Request| async move {
+                        if req.peer_addr().unwrap().is_unix() {
+                            Ok(Response::not_found())
+                        }
+                        else {
+                            Err(Response::bad_request())
+                        }
+                    },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        let mut sock = loop {
+            if let Ok(sock) = tokio::net::UnixStream::connect(&path).await {
+                break sock
+            }
+            tokio::task::yield_now().await;
+        };
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let meta = std::fs::metadata(&path).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+            let mut tmp_name = std::ffi::OsString::from(".");
+            tmp_name.push(path.file_name().unwrap());
+            tmp_name.push(format!(".{}", std::process::id()));
+            assert!(!path.with_file_name(tmp_name).exists());
+        }
+        sock.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        sock.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 404"));
+
+        tx.send(()).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), Shutdown::Clean);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,469 @@
+//! Serving HTTPS.
+#![cfg(feature = "tls")]
+
+use std::{error, fmt, io};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::time::Timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::server::TlsStream;
+
+
+//------------ TlsConfig -----------------------------------------------------
+
+/// The TLS configuration for a listening socket.
+///
+/// The configuration holds a certificate chain and private key read from
+/// PEM files. Both can be reloaded while the server is running via
+/// [`reload`][Self::reload] or one of the `reload_on_*` methods. New
+/// connections will use the new certificate while established ones are
+/// unaffected.
+///
+/// Values can be cheaply cloned and all clones share the same certificate.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    resolver: Arc<CertResolver>,
+}
+
+impl TlsConfig {
+    /// Creates a new config from a certificate chain and key file.
+    ///
+    /// The certificate file must contain the PEM encoded certificate
+    /// chain starting with the end-entity certificate. The key file must
+    /// contain the PEM encoded private key.
+    pub fn from_pem_files(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Result<Self, TlsError> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let provider = Arc::new(ring::default_provider());
+        let modified = modified(&cert_path, &key_path);
+        let key = load_key(&cert_path, &key_path, &provider)?;
+        Ok(TlsConfig {
+            resolver: Arc::new(CertResolver {
+                cert_path, key_path, provider,
+                current: RwLock::new((Arc::new(key), modified)),
+            })
+        })
+    }
+
+    /// Reloads the certificate chain and key from their files.
+    ///
+    /// If loading fails, the current certificate is kept.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let resolver = &self.resolver;
+        let modified = modified(&resolver.cert_path, &resolver.key_path);
+        let key = load_key(
+            &resolver.cert_path, &resolver.key_path, &resolver.provider
+        )?;
+        *resolver.current.write().expect("poisoned lock") = (
+            Arc::new(key), modified
+        );
+        Ok(())
+    }
+
+    /// Reloads the certificate whenever the process receives SIGHUP.
+    ///
+    /// The returned future never resolves and needs to be spawned. If
+    /// reloading fails, `on_error` is called with the error and the
+    /// current certificate is kept.
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self, on_error: impl Fn(TlsError)) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(hup) => hup,
+            Err(err) => {
+                on_error(TlsError::Signal(err));
+                return std::future::pending().await
+            }
+        };
+        while hup.recv().await.is_some() {
+            if let Err(err) = self.reload() {
+                on_error(err)
+            }
+        }
+    }
+
+    /// Reloads the certificate whenever one of its files changes.
+    ///
+    /// The files’ modification times are checked every `interval`. The
+    /// returned future never resolves and needs to be spawned. If
+    /// reloading fails, `on_error` is called with the error and the
+    /// current certificate is kept until the files change again.
+    pub async fn reload_on_change(
+        self, interval: Duration, on_error: impl Fn(TlsError)
+    ) {
+        let resolver = &self.resolver;
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = modified(&resolver.cert_path, &resolver.key_path);
+            if modified == resolver.current.read().expect("poisoned lock").1 {
+                continue
+            }
+            if let Err(err) = self.reload() {
+                // Remember the times so we don’t retry until the next
+                // change.
+                resolver.current.write().expect("poisoned lock").1 = modified;
+                on_error(err)
+            }
+        }
+    }
+
+    /// Creates a TLS acceptor advertising the given ALPN protocols.
+    pub(crate) fn acceptor(&self, alpn: &[&[u8]]) -> TlsAcceptor {
+        let mut config = ServerConfig::builder_with_provider(
+            self.resolver.provider.clone()
+        ).with_safe_default_protocol_versions()
+        .expect("default provider supports default versions")
+        .with_no_client_auth()
+        .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = alpn.iter().map(|item| {
+            item.to_vec()
+        }).collect();
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+
+//------------ CertResolver --------------------------------------------------
+
+/// The certificate resolver allowing the certificate to be replaced.
+#[derive(Debug)]
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+
+    /// The current certificate and the modification times of its files.
+    current: RwLock<(Arc<CertifiedKey>, Modified)>,
+}
+
+/// The modification times of the certificate and key files.
+type Modified = (Option<SystemTime>, Option<SystemTime>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("poisoned lock").0.clone())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Modified {
+    let modified = |path| {
+        std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    };
+    (modified(cert_path), modified(key_path))
+}
+
+fn load_key(
+    cert_path: &Path, key_path: &Path, provider: &CryptoProvider
+) -> Result<CertifiedKey, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert_path).and_then(|iter| {
+        iter.collect::<Result<Vec<_>, _>>()
+    }).map_err(|err| TlsError::Pem { path: cert_path.into(), err })?;
+    if certs.is_empty() {
+        return Err(TlsError::Pem {
+            path: cert_path.into(), err: pem::Error::NoItemsFound
+        })
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| {
+        TlsError::Pem { path: key_path.into(), err }
+    })?;
+    CertifiedKey::from_der(certs, key, provider).map_err(TlsError::Key)
+}
+
+
+//------------ TlsIncoming ---------------------------------------------------
+
+/// How long we wait for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A listening socket performing TLS handshakes on accepted connections.
+///
+/// Handshakes are performed concurrently, so a slow client does not hold
+/// up accepting other connections. Connections whose handshake fails or
+/// takes too long are silently dropped. While `limit` handshakes are in
+/// progress, no further connections are accepted.
+pub(crate) struct TlsIncoming {
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<
+        Timeout<tokio_rustls::Accept<AddrStream>>
+    >,
+    limit: usize,
+}
+
+impl TlsIncoming {
+    pub(crate) fn new(
+        incoming: AddrIncoming, acceptor: TlsAcceptor, limit: usize
+    ) -> Self {
+        TlsIncoming {
+            incoming, acceptor,
+            handshakes: FuturesUnordered::new(),
+            limit: limit.max(1),
+        }
+    }
+
+    /// Returns the local address of the listener.
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.incoming.local_addr()
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<AddrStream>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            while this.handshakes.len() < this.limit {
+                match Pin::new(&mut this.incoming).poll_accept(cx) {
+                    Poll::Ready(Some(Ok(stream))) => {
+                        this.handshakes.push(tokio::time::timeout(
+                            HANDSHAKE_TIMEOUT, this.acceptor.accept(stream)
+                        ))
+                    }
+                    Poll::Ready(Some(Err(err))) => {
+                        return Poll::Ready(Some(Err(err)))
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => break,
+                }
+            }
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => {
+                    return Poll::Ready(Some(Ok(stream)))
+                }
+                // A failed handshake frees a slot, so try accepting again.
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+//------------ TlsError ------------------------------------------------------
+
+/// An error happened while loading the TLS configuration.
+#[derive(Debug)]
+pub enum TlsError {
+    /// Reading a PEM file failed.
+    Pem {
+        /// The path of the file.
+        path: PathBuf,
+
+        /// The underlying error.
+        err: pem::Error,
+    },
+
+    /// The private key was not acceptable.
+    Key(rustls::Error),
+
+    /// Listening for a signal failed.
+    Signal(io::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Pem { path, err } => {
+                write!(f, "{}: {}", path.display(), err)
+            }
+            TlsError::Key(err) => {
+                write!(f, "invalid private key: {}", err)
+            }
+            TlsError::Signal(err) => {
+                write!(f, "failed to listen for signal: {}", err)
+            }
+        }
+    }
+}
+
+impl error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TlsError::Pem { err, .. } => Some(err),
+            TlsError::Key(err) => Some(err),
+            TlsError::Signal(err) => Some(err),
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use crate::response::Response;
+    use crate::server::{ServerBuilder, Shutdown};
+
+    /// Writes a new self-signed certificate and key to temporary files.
+    fn write_cert(
+        name: &str
+    ) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(
+            vec!["localhost".into()]
+        ).unwrap();
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let cert_path = dir.join(format!("httools-{}-{}.crt", name, pid));
+        let key_path = dir.join(format!("httools-{}-{}.key", name, pid));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+        (cert_path, key_path, cert.cert.der().clone())
+    }
+
+    /// Creates a TLS connector trusting only the given certificate.
+    fn connector(cert: CertificateDer<'static>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client = ClientConfig::builder_with_provider(
+            Arc::new(ring::default_provider())
+        ).with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        client.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsConnector::from(Arc::new(client))
+    }
+
+    /// Connects to the given address and performs the TLS handshake.
+    async fn connect(
+        addr: std::net::SocketAddr, connector: TlsConnector
+    ) -> tokio_rustls::client::TlsStream<tokio::net::TcpStream> {
+        let sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        connector.connect(
+            ServerName::try_from("localhost").unwrap(), sock
+        ).await.unwrap()
+    }
+
+    #[test]
+    fn load_and_reload() {
+        let (cert_path, key_path, _) = write_cert("reload");
+        let config = TlsConfig::from_pem_files(
+            &cert_path, &key_path
+        ).unwrap();
+        config.reload().unwrap();
+
+        std::fs::write(&key_path, "").unwrap();
+        assert!(matches!(
+            config.reload(),
+            Err(TlsError::Pem { path, .. }) if path == key_path
+        ));
+
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(TlsConfig::from_pem_files(&cert_path, &key_path).is_err());
+    }
+
+    #[tokio::test]
+    async fn serve_tls() {
+        let (cert_path, key_path, cert) = write_cert("serve");
+        let config = TlsConfig::from_pem_files(
+            &cert_path, &key_path
+        ).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .tls_listener(listener, config)
+                .serve_with_shutdown(
+                    Arc::new(()),
+                    |_, req: crate::request::Request| async move {
+                        if req.is_tls() {
+                            Ok(Response::not_found())
+                        }
+                        else {
+                            Err(Response::bad_request())
+                        }
+                    },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        let mut sock = connect(addr, connector(cert)).await;
+        assert_eq!(sock.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+        sock.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = Vec::new();
+        let _ = sock.read_to_end(&mut resp).await;
+        assert!(resp.starts_with(b"HTTP/1.0 404"));
+
+        tx.send(()).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), Shutdown::Clean);
+    }
+
+    #[tokio::test]
+    async fn handshake_limit() {
+        use std::time::Duration;
+
+        let (cert_path, key_path, cert) = write_cert("limit");
+        let config = TlsConfig::from_pem_files(
+            &cert_path, &key_path
+        ).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new()
+                .tls_listener(listener, config)
+                .tls_handshake_limit(Some(1))
+                .serve_with_shutdown(
+                    Arc::new(()),
+                    |_, _| async { Ok(Response::not_found()) },
+                    async { let _ = rx.await; },
+                )
+        );
+
+        // A client that never starts its handshake takes the only slot.
+        let stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = tokio::spawn(connect(addr, connector(cert)));
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(300), &mut client
+            ).await.is_err()
+        );
+
+        // Once it gives up, the next handshake can go ahead.
+        drop(stalled);
+        let mut sock = client.await.unwrap();
+        sock.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = Vec::new();
+        let _ = sock.read_to_end(&mut resp).await;
+        assert!(resp.starts_with(b"HTTP/1.0 404"));
+
+        tx.send(()).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), Shutdown::Clean);
+    }
+}